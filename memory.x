@@ -1,9 +1,13 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
     FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
-    RAM   : ORIGIN = 0x20000000, LENGTH = 256K
+    /* Striped SRAM0-3 followed by SRAM4 and SRAM5 */
+    RAM   : ORIGIN = 0x20000000, LENGTH = 264K
 }
 
+/* Stack of core0, growing down from the end of RAM */
+_stack_size = 16K;
+
 EXTERN(BOOT2_FIRMWARE)
 
 SECTIONS {
@@ -12,4 +16,17 @@ SECTIONS {
     {
         KEEP(*(.boot2));
     } > BOOT2
-} INSERT BEFORE .text;
\ No newline at end of file
+} INSERT BEFORE .text;
+
+SECTIONS {
+    /* ### Sample memory, everything between the statics and the stack */
+    .samples (NOLOAD) : ALIGN(4)
+    {
+        _samples_start = .;
+        . = ORIGIN(RAM) + LENGTH(RAM) - _stack_size;
+        _samples_end = .;
+    } > RAM
+} INSERT AFTER .uninit;
+
+ASSERT(_samples_end - _samples_start >= 128K, "
+ERROR(ula): less than 128 KB left for sample memory, the statics have grown too large.");
\ No newline at end of file
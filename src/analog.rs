@@ -0,0 +1,349 @@
+use crate::*;
+use core::sync::atomic::{compiler_fence, Ordering};
+use hal::adc::{Adc, AdcPin};
+
+/// Number of analog samples the ring holds.
+pub const ANALOG_LEN: usize = 2048;
+/// Analog inputs available on GPIO 26-29.
+pub const ANALOG_INPUTS: u8 = 4;
+/// ADC clock in Hertz, taken from PLL_USB so it doesn't follow the system clock.
+const ADC_CLOCK: u32 = 48_000_000;
+/// ADC clocks a conversion takes, the shortest conversion period.
+const CONVERSION: u32 = 96;
+
+/// Type alias for a GPIO handed to the ADC.
+type Input<I> = AdcPin<Pin<I, FunctionSioInput, PullNone>>;
+
+/// Type alias for the analog inputs on GPIO 26-29.
+pub type AnalogPins = (
+    Input<bank0::Gpio26>,
+    Input<bank0::Gpio27>,
+    Input<bank0::Gpio28>,
+    Input<bank0::Gpio29>,
+);
+
+/// Analog ring, aligned to its size so the DMA can wrap around it.
+#[repr(C, align(4096))]
+pub struct AnalogRing([u16; ANALOG_LEN]);
+
+const _: () = assert!(core::mem::align_of::<AnalogRing>() == 2 * ANALOG_LEN);
+
+/// Struct representing the analog capture next to the digital one.
+///
+/// The ADC free-runs over the enabled inputs in round robin, its data
+/// channel wraps around the analog ring. It starts right before the capture
+/// state machine, and the stop channel of the sample ring chains to the halt
+/// channel, which clears `START_MANY` on the same DMA event that stops the
+/// digital capture. The newest analog sample was therefore taken within one
+/// conversion period before the newest digital one.
+pub struct Analog {
+    /// ADC registers, brought out of reset and enabled.
+    adc: pac::ADC,
+    /// Inputs with their digital circuitry disabled.
+    _pins: AnalogPins,
+    /// Channel moving conversions into the ring.
+    data: Channel<CH6>,
+    /// Channel halting the conversions when the sample ring stops.
+    halt: Channel<CH5>,
+    /// Analog sample ring.
+    ring: &'static AnalogRing,
+    /// ADC control word with `START_MANY` cleared, copied by the halt channel.
+    control: &'static mut u32,
+    /// Enabled inputs, bit 0 for GPIO 26, zero disables the analog capture.
+    inputs: u8,
+    /// Clock divider of the ADC in 16.8 fixed point.
+    divider: (u16, u8),
+    /// Whether conversions run along the current capture.
+    active: bool,
+    /// Number of conversions of the last capture.
+    captured: u32,
+}
+
+impl Analog {
+    /// Creates a new instance of the analog capture.
+    ///
+    /// # Arguments
+    ///
+    /// * `adc` - ADC, brought out of reset.
+    /// * `pins` - Inputs handed to the ADC.
+    /// * `ch` - DMA channels halting the conversions and moving them into the ring.
+    ///
+    /// # Returns
+    ///
+    /// A new `Analog` instance with all inputs disabled.
+    pub fn new(adc: Adc, pins: AnalogPins, ch: (Channel<CH5>, Channel<CH6>)) -> Self {
+        let (halt, data) = ch;
+        Self {
+            adc: adc.free(),
+            _pins: pins,
+            data,
+            halt,
+            ring: singleton!(: AnalogRing = AnalogRing([0x00; ANALOG_LEN])).unwrap(),
+            control: singleton!(: u32 = 0x00).unwrap(),
+            inputs: 0,
+            divider: (0, 0),
+            active: false,
+            captured: 0,
+        }
+    }
+
+    /// Selects the inputs converted along the following captures.
+    ///
+    /// # Arguments
+    ///
+    /// * `inputs` - Bit mask of the inputs, bit 0 for GPIO 26, zero disables the analog capture.
+    pub fn set_inputs(&mut self, inputs: u8) {
+        self.inputs = inputs & ((1 << ANALOG_INPUTS) - 1);
+    }
+
+    /// Checks whether any input is enabled.
+    pub fn enabled(&self) -> bool {
+        self.inputs != 0
+    }
+
+    /// Sets the conversion rate, shared by the enabled inputs in round robin.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - Conversions per second, clamped to 732 to 500000.
+    ///
+    /// # Returns
+    ///
+    /// The conversion rate achieved, rounded down.
+    pub fn set_rate(&mut self, rate: u32) -> u32 {
+        self.divider = adc_divider(rate);
+        self.rate()
+    }
+
+    /// Returns the conversion rate.
+    ///
+    /// # Returns
+    ///
+    /// Conversions per second over all enabled inputs, rounded down.
+    pub fn rate(&self) -> u32 {
+        adc_rate(self.divider)
+    }
+
+    /// Returns the channel the stop channel of the sample ring chains to.
+    ///
+    /// # Returns
+    ///
+    /// The number of the halt channel.
+    pub fn halt_channel(&self) -> u8 {
+        self.halt.id()
+    }
+
+    /// Starts converting the enabled inputs into the ring.
+    ///
+    /// Call right before the capture state machine starts, so both
+    /// captures start together.
+    pub fn start(&mut self) {
+        self.abort();
+        if !self.enabled() {
+            return;
+        }
+
+        // Drop stale conversions, then let the FIFO request the DMA for every sample.
+        self.adc
+            .fcs()
+            .write(|w| unsafe { w.en().set_bit().dreq_en().set_bit().thresh().bits(1) });
+        while self.adc.fcs().read().level().bits() > 0 {
+            self.adc.fifo().read();
+        }
+        self.adc
+            .div()
+            .write(|w| unsafe { w.int().bits(self.divider.0).frac().bits(self.divider.1) });
+        let first = self.inputs.trailing_zeros() as u8;
+        self.adc.cs().write(|w| unsafe {
+            w.en()
+                .set_bit()
+                .ainsel()
+                .bits(first)
+                .rrobin()
+                .bits(self.inputs)
+        });
+        *self.control = self.adc.cs().read().bits();
+
+        let halt = self.halt.ch();
+        unsafe {
+            halt.ch_read_addr()
+                .write(|w| w.bits(&*self.control as *const u32 as u32));
+            halt.ch_write_addr()
+                .write(|w| w.bits(self.adc.cs().as_ptr() as u32));
+            halt.ch_trans_count().write(|w| w.bits(1));
+            // Armed without a trigger, the stop channel chains to it.
+            halt.ch_al1_ctrl().write(|w| {
+                w.data_size()
+                    .size_word()
+                    .treq_sel()
+                    .permanent()
+                    .chain_to()
+                    .bits(self.halt.id())
+                    .irq_quiet()
+                    .set_bit()
+                    .en()
+                    .set_bit()
+            });
+        }
+
+        let data = self.data.ch();
+        unsafe {
+            data.ch_read_addr()
+                .write(|w| w.bits(self.adc.fifo().as_ptr() as u32));
+            data.ch_write_addr()
+                .write(|w| w.bits(self.ring.0.as_ptr() as u32));
+            data.ch_al1_ctrl().write(|w| {
+                w.data_size()
+                    .size_halfword()
+                    .incr_write()
+                    .set_bit()
+                    .ring_sel()
+                    .set_bit()
+                    .ring_size()
+                    .bits((2 * ANALOG_LEN).trailing_zeros() as u8)
+                    .treq_sel()
+                    .adc()
+                    .chain_to()
+                    .bits(self.data.id())
+                    .irq_quiet()
+                    .set_bit()
+                    .en()
+                    .set_bit()
+            });
+            // The count only tells how many conversions landed, the ring wraps by itself.
+            data.ch_al1_trans_count_trig().write(|w| w.bits(u32::MAX));
+        }
+        self.adc.cs().modify(|_, w| w.start_many().set_bit());
+        self.active = true;
+    }
+
+    /// Collects the conversions once the halt channel stopped the ADC.
+    pub fn finish(&mut self) {
+        if !self.active {
+            return;
+        }
+        // The conversion in flight when the ADC was halted lands last.
+        while self.adc.cs().read().ready().bit_is_clear() {}
+        while self.adc.fcs().read().level().bits() > 0 {}
+        let captured = u32::MAX - self.data.ch().ch_trans_count().read().bits();
+        self.abort();
+        self.captured = captured;
+    }
+
+    /// Stops the conversions and both channels, dropping the samples kept.
+    pub fn abort(&mut self) {
+        self.adc.cs().modify(|_, w| w.start_many().clear_bit());
+        for ch in [self.data.ch(), self.halt.ch()] {
+            ch.ch_al1_ctrl().modify(|_, w| w.en().clear_bit());
+        }
+        let mask = 1 << self.data.id() | 1 << self.halt.id();
+        unsafe {
+            let dma = &*pac::DMA::ptr();
+            dma.chan_abort().write(|w| w.bits(mask));
+            while dma.chan_abort().read().bits() != 0 {}
+        }
+        self.active = false;
+        self.captured = 0;
+    }
+
+    /// Returns the conversions of the last capture.
+    ///
+    /// # Returns
+    ///
+    /// The conversion rate, the enabled inputs, the input of the newest
+    /// sample and the number of samples kept.
+    pub fn captured(&self) -> (u32, u8, u8, usize) {
+        let newest = newest_input(self.inputs, self.captured);
+        let kept = (self.captured as usize).min(ANALOG_LEN);
+        (self.rate(), self.inputs, newest, kept)
+    }
+
+    /// Returns a sample of the last capture.
+    ///
+    /// # Arguments
+    ///
+    /// * `age` - Number of samples taken after it, zero is the newest one.
+    ///
+    /// # Returns
+    ///
+    /// The 12-bit conversion result.
+    pub fn sample(&self, age: usize) -> u16 {
+        // Keep sample reads after the DMA has stopped.
+        compiler_fence(Ordering::SeqCst);
+        let idx = (self.captured as usize % ANALOG_LEN + ANALOG_LEN - 1 - age) % ANALOG_LEN;
+        unsafe { core::ptr::read_volatile(&self.ring.0[idx]) }
+    }
+}
+
+/// Computes the ADC clock divider for a conversion rate.
+///
+/// # Arguments
+///
+/// * `rate` - Conversions per second.
+///
+/// # Returns
+///
+/// The integer and fractional parts of the divider, clamped to the shortest
+/// conversion period and the longest period the divider counts.
+fn adc_divider(rate: u32) -> (u16, u8) {
+    // The divider counts ADC clocks between conversions minus one, in 16.8 fixed point.
+    let period = (ADC_CLOCK as u64 * 256 / rate.max(1) as u64)
+        .clamp(CONVERSION as u64 * 256, 0x100_0000) as u32
+        - 256;
+    ((period >> 8) as u16, period as u8)
+}
+
+/// Computes the conversion rate of an ADC clock divider.
+///
+/// # Arguments
+///
+/// * `divider` - Integer and fractional parts of the divider.
+///
+/// # Returns
+///
+/// Conversions per second, rounded down.
+fn adc_rate(divider: (u16, u8)) -> u32 {
+    let period = ((divider.0 as u64) << 8 | divider.1 as u64) + 256;
+    (ADC_CLOCK as u64 * 256 / period.max(CONVERSION as u64 * 256)) as u32
+}
+
+/// Finds the input converted last.
+///
+/// # Arguments
+///
+/// * `inputs` - Enabled inputs, bit 0 for GPIO 26.
+/// * `captured` - Number of conversions taken.
+///
+/// # Returns
+///
+/// The input of the newest sample, zero without any conversion.
+fn newest_input(inputs: u8, captured: u32) -> u8 {
+    // Inputs take turns in increasing order from the lowest enabled one.
+    let mut enabled = (0..ANALOG_INPUTS).filter(|input| inputs >> input & 1 == 1);
+    match (inputs.count_ones(), captured) {
+        (0, _) | (_, 0) => 0,
+        (count, captured) => enabled.nth(((captured - 1) % count) as usize).unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_round_down_within_the_adc_limits() {
+        assert_eq!(adc_rate(adc_divider(500_000)), 500_000);
+        assert_eq!(adc_rate(adc_divider(1_000_000)), 500_000);
+        assert_eq!(adc_rate(adc_divider(44_100)), 44_100);
+        assert!(adc_rate(adc_divider(1)) >= 732);
+    }
+
+    #[test]
+    fn round_robin_ends_on_the_newest_input() {
+        assert_eq!(newest_input(0b0000, 5), 0);
+        assert_eq!(newest_input(0b1010, 0), 0);
+        assert_eq!(newest_input(0b1010, 1), 1);
+        assert_eq!(newest_input(0b1010, 2), 3);
+        assert_eq!(newest_input(0b1101, 5), 2);
+    }
+}
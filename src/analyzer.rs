@@ -11,10 +11,12 @@ enum SumpCommand {
     GetId,
     /// Get metadata information about the analyzer.
     GetMeta,
+    /// Get the status of the last capture.
+    GetStatus,
     /// Set the sampling divisor.
-    SetDivisor(u16),
-    /// Set the number of samples to read.
-    SetReadCount(usize),
+    SetDivisor(u32),
+    /// Set the number of samples to read and the number captured after the trigger.
+    SetReadCount(usize, usize),
     /// Set specific configuration flags.
     SetFlags(u8),
     /// Set the trigger mask for a specific stage.
@@ -23,6 +25,86 @@ enum SumpCommand {
     SetTriggerValues(u8, u32),
     /// Set the trigger delay for a specific stage.
     SetTriggerDelay(u8, u32),
+    /// Set the trigger occurrence count for a specific stage.
+    SetTriggerCount(u8, u32),
+    /// Set the auto-trigger timeout in milliseconds.
+    SetAutoTrigger(u32),
+    /// Set the trigger output pin and its flags.
+    SetTriggerOutput(u8, u8),
+    /// Set the external trigger input pin and its flags for a specific stage.
+    SetTriggerExternal(u8, u8, u8),
+    /// Set the trigger OR group for a specific stage.
+    SetTriggerGroup(u8, u8),
+    /// Set the window the stage following a specific stage has to match within.
+    SetTriggerWindow(u8, u32),
+    /// Set the bus range channels and flags for a specific stage.
+    SetTriggerRange(u8, u8, u8, u8),
+    /// Set the bus range bounds for a specific stage.
+    SetTriggerRangeBounds(u8, u16, u16),
+    /// Select the protocol trigger and its parameters.
+    SetProtocolTrigger(u8, [u8; 3]),
+    /// Set the bit rate of the protocol trigger.
+    SetProtocolRate(u32),
+    /// Set the value the protocol trigger matches.
+    SetProtocolValue(u32),
+    /// Set the mask of the bits the protocol trigger compares.
+    SetProtocolMask(u32),
+    /// Append bytes to the trigger expression.
+    AppendExpression([u8; 4]),
+    /// Compile the trigger expression into the trigger stages.
+    CompileExpression,
+    /// Select continuous streaming with its flags.
+    SetStreaming(u8),
+    /// Get the highest sampling rate USB can sustain while streaming.
+    GetStreamRate,
+    /// Select transition sampling with its flags.
+    SetTransitions(u8),
+    /// Set the number of samples a transition capture spans.
+    SetTransitionSpan(u32),
+    /// Select compression on core1 with its flags.
+    SetCompression(u8),
+    /// Get how the last capture was stored and its compression ratio.
+    GetCompression,
+    /// Limit captures to the first channels of the enabled channel group.
+    SetChannels(u8),
+    /// Get the capture depth for each sample width.
+    GetDepth,
+    /// Split the sample memory into segments captured on successive triggers.
+    SetSegments(u8),
+    /// Select repeat mode with its flags.
+    SetRepeat(u8),
+    /// Reprogram the system clock, which is also the highest sampling rate.
+    SetSysClock(u32),
+    /// Set the sampling rate in Hertz instead of the divisor.
+    SetSampleRate(u32),
+    /// Select the analog inputs converted along the captures.
+    SetAnalogInputs(u8),
+    /// Set the conversion rate of the analog capture in Hertz.
+    SetAnalogRate(u32),
+    /// Get the analog samples of the last capture.
+    GetAnalog,
+    /// Select the input synchronizer bypass with its flags.
+    SetSyncBypass(u8),
+}
+
+/// Enumeration of capture states reported by the `GetStatus` command.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum CaptureStatus {
+    /// No capture has been armed yet.
+    Idle = 0,
+    /// Waiting for the trigger to match.
+    Armed = 1,
+    /// Capture was started by the trigger.
+    Triggered = 2,
+    /// Capture was started by the auto-trigger timeout without a trigger match.
+    AutoTriggered = 3,
+    /// Samples are being streamed until reset.
+    Streaming = 4,
+    /// Streaming stopped because USB didn't keep up with the sampling rate.
+    Overrun = 5,
+    /// Arming was refused because the trigger programs don't fit PIO memory.
+    Rejected = 6,
 }
 
 /// Type alias for the status LED pin configuration.
@@ -38,8 +120,24 @@ pub struct LogicAnalyzer {
     status_led: Led,
     /// Sampler responsible for data acquisition.
     sampler: Sampler,
+    /// System clock the sampling rates derive from.
+    sys_clock: SysClock,
     /// Trigger settings and configurations.
     trigger: Trigger,
+    /// Auto-trigger timeout in milliseconds, zero disables it.
+    auto_trigger: u32,
+    /// Instant when the pending capture is forced to start.
+    auto_trigger_deadline: Option<Instant>,
+    /// Status of the last capture.
+    status: CaptureStatus,
+    /// Whether the analyzer re-arms itself once a capture has been uploaded.
+    repeat: bool,
+    /// Buffer for the trigger expression being received.
+    expression: [u8; EXPR_LEN],
+    /// Number of expression bytes received, may exceed the buffer size.
+    expression_len: usize,
+    /// Bus range bounds for each stage, applied by the `SetTriggerRange` command.
+    range_bounds: [(u16, u16); 4],
     /// Index used for parsing incoming commands.
     needle: usize,
     /// Buffer for storing incoming serial data.
@@ -53,9 +151,8 @@ impl LogicAnalyzer {
     ///
     /// * `usb_dev` - USB device instance.
     /// * `serial` - Serial port for USB communication.
-    /// * `pio` - PIO instance for handling programmable I/O.
-    /// * `sm` - Uninitialized state machine for PIO.
-    /// * `dma` - DMA channels for data transfer.
+    /// * `sampler` - Sampler responsible for data acquisition.
+    /// * `sys_clock` - System clock the sampling rates derive from.
     /// * `status_led` - LED pin for status indication.
     ///
     /// # Returns
@@ -64,45 +161,93 @@ impl LogicAnalyzer {
     pub fn new(
         usb_dev: UsbDevice<'static, UsbBus>,
         serial: SerialPort<'static, UsbBus>,
-        pio: PIO<pac::PIO0>,
-        sm: UninitStateMachine<(pac::PIO0, SM0)>,
-        dma: dma::Channels,
+        sampler: Sampler,
+        sys_clock: SysClock,
         status_led: Led,
     ) -> Self {
-        let sampler = Sampler::new(pio, sm, dma);
         Self {
             sampler,
+            sys_clock,
             serial,
             usb_dev,
             status_led,
             needle: 0,
             scratch: [0; 64],
             trigger: Default::default(),
+            auto_trigger: 0,
+            auto_trigger_deadline: None,
+            status: CaptureStatus::Idle,
+            repeat: false,
+            expression: [0; EXPR_LEN],
+            expression_len: 0,
+            range_bounds: [(0, 0); 4],
         }
     }
 
     /// Called when data acquisition is complete.
     ///
-    /// Drains the sampler's data into the serial port and turns off the status LED.
+    /// Starts uploading the sampler's data and turns off the status LED, or
+    /// sends the streamed samples and flags an overrun.
     pub fn acquisition_done(&mut self) {
-        self.sampler.drain(&mut self.serial);
-        self.status_led.set_low().unwrap();
+        if self.sampler.drain(&mut self.serial) {
+            self.status_led.set_low().unwrap();
+            self.auto_trigger_deadline = None;
+            if self.status == CaptureStatus::Armed {
+                self.status = CaptureStatus::Triggered;
+            }
+        }
+        if self.status == CaptureStatus::Streaming && self.sampler.stream_overrun() {
+            self.status_led.set_low().unwrap();
+            self.status = CaptureStatus::Overrun;
+        }
+    }
+
+    /// Returns the instant when the pending capture should be forced to start.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the auto-trigger is disabled or no capture is waiting for a trigger.
+    pub fn auto_trigger_deadline(&self) -> Option<Instant> {
+        self.auto_trigger_deadline
+    }
+
+    /// Forces the pending capture to start if the trigger hasn't matched yet.
+    ///
+    /// The capture is flagged as auto-triggered in the status, unless it
+    /// doesn't wait for a trigger.
+    pub fn force_trigger(&mut self) {
+        self.auto_trigger_deadline = None;
+        if self.status == CaptureStatus::Armed
+            && !self.sampler.triggered()
+            && self.sampler.force_trigger()
+        {
+            self.status = CaptureStatus::AutoTriggered;
+        }
     }
 
     /// Polls the serial interface for incoming commands and processes them.
+    ///
+    /// Also resumes a pending upload once the serial port has room again.
     pub fn poll_serial(&mut self) {
         if self.usb_dev.poll(&mut [&mut self.serial]) {
             // If a new command is received, parse and execute it.
             if let Some(cmd) = self.parse_command() {
                 match cmd {
                     SumpCommand::Reset => {
-                        // Reset the needle index.
+                        // Reset the needle index and drop a partial trigger expression.
                         self.needle = 0;
+                        self.expression_len = 0;
+                        // Stop repeating captures and a streaming session.
+                        self.repeat = false;
+                        self.sampler.stop_stream();
+                        if self.status == CaptureStatus::Streaming {
+                            self.status_led.set_low().unwrap();
+                            self.status = CaptureStatus::Idle;
+                        }
                     }
                     SumpCommand::Arm => {
-                        // Activate the status LED and start the sampler with the current trigger.
-                        self.status_led.set_high().unwrap();
-                        self.sampler.start(self.trigger);
+                        // Start a capture with the current trigger.
+                        self.arm();
                     }
                     SumpCommand::SetFlags(flags) => {
                         // Set configuration flags in the sampler.
@@ -112,9 +257,10 @@ impl LogicAnalyzer {
                         // Set the sampling divisor in the sampler.
                         self.sampler.set_divisor(divisor);
                     }
-                    SumpCommand::SetReadCount(samples) => {
-                        // Set the number of samples to read in the sampler.
+                    SumpCommand::SetReadCount(samples, delay) => {
+                        // Set the number of samples to read and the trigger position in the sampler.
                         self.sampler.set_sample_memory(samples);
+                        self.sampler.set_delay_count(delay);
                     }
                     SumpCommand::SetTriggerMask(stage, mask) if stage < 4 => {
                         // Set the trigger mask for a specific stage.
@@ -128,6 +274,198 @@ impl LogicAnalyzer {
                         // Set the trigger delay for a specific stage.
                         self.trigger.set_delay(stage as _, delay);
                     }
+                    SumpCommand::SetTriggerCount(stage, count) if stage < 4 => {
+                        // Set the trigger occurrence count for a specific stage.
+                        // Counts that don't fit PIO memory are ignored.
+                        self.trigger.set_count(stage as _, count);
+                    }
+                    SumpCommand::SetTriggerWindow(stage, window) if stage < 4 => {
+                        // Set the window the next stage has to match within.
+                        // Windows that don't fit PIO memory are ignored.
+                        self.trigger.set_window(stage as _, window);
+                    }
+                    SumpCommand::SetTriggerRangeBounds(stage, min, max) if stage < 4 => {
+                        // Store the bounds until the range is enabled.
+                        self.range_bounds[stage as usize] = (min, max);
+                    }
+                    SumpCommand::SetTriggerRange(stage, channel, width, flags) if stage < 4 => {
+                        // Configure the bus range, bit 0 of flags enables it, bit 1 inverts it.
+                        let (min, max) = self.range_bounds[stage as usize];
+                        let range =
+                            (flags & 1 == 1).then_some((channel, width, min, max, flags & 2 != 0));
+                        // Buses past the probes and ranges exceeding the instruction budget are ignored.
+                        self.trigger.set_range(stage as _, range);
+                    }
+                    SumpCommand::SetAutoTrigger(timeout) => {
+                        // Set the auto-trigger timeout for the following captures.
+                        self.auto_trigger = timeout;
+                    }
+                    SumpCommand::SetTriggerOutput(_, flags) if flags & 1 == 0 => {
+                        // Disable the trigger output, whichever pin is named.
+                        self.trigger.set_output(None);
+                    }
+                    SumpCommand::SetTriggerOutput(pin, flags) if AUX_PINS.contains(&pin) => {
+                        // Enable the trigger output pin, bit 1 of flags inverts it, bit 2 pulses it.
+                        self.trigger
+                            .set_output(Some((pin, flags & 2 != 0, flags & 4 != 0)));
+                    }
+                    SumpCommand::SetTriggerOutput(..) => {
+                        // Reject pins outside the auxiliary range, the output stays as it is.
+                    }
+                    SumpCommand::SetTriggerExternal(stage, _, flags)
+                        if stage < 4 && flags & 1 == 0 =>
+                    {
+                        // Disable the external trigger input, whichever pin is named.
+                        self.trigger.set_external(stage as _, None);
+                    }
+                    SumpCommand::SetTriggerExternal(stage, pin, flags)
+                        if stage < 4 && AUX_PINS.contains(&pin) =>
+                    {
+                        // Enable the external trigger input, bit 1 of flags inverts it, bit 2 waits for an edge.
+                        self.trigger
+                            .set_external(stage as _, Some((pin, flags & 2 != 0, flags & 4 != 0)));
+                    }
+                    SumpCommand::SetTriggerGroup(stage, group)
+                        if stage < 4 && (group as usize) < TRIGGER_GROUPS =>
+                    {
+                        // Assign the stage to an OR group.
+                        self.trigger.set_group(stage as _, group);
+                    }
+                    SumpCommand::SetProtocolTrigger(kind, params) => {
+                        // Select the protocol decoder, unknown kinds disable it and
+                        // decoders that don't fit PIO memory are ignored.
+                        self.trigger
+                            .set_protocol(ProtocolTrigger::new(kind, params));
+                    }
+                    SumpCommand::SetProtocolRate(rate) => {
+                        // Set the bit rate of the protocol decoder, kept across decoder changes.
+                        self.trigger.set_protocol_rate(rate);
+                    }
+                    SumpCommand::SetProtocolValue(value) => {
+                        // Set the value the protocol decoder matches, kept across decoder changes.
+                        self.trigger.set_protocol_value(value);
+                    }
+                    SumpCommand::SetProtocolMask(mask) => {
+                        // Set the mask of the bits the protocol decoder compares, kept across decoder changes.
+                        // Masks that don't fit PIO memory are ignored.
+                        self.trigger.set_protocol_mask(mask);
+                    }
+                    SumpCommand::AppendExpression(bytes) => {
+                        // Append the bytes to the expression buffer, NUL bytes pad the last chunk.
+                        for byte in bytes.into_iter().filter(|byte| *byte != 0) {
+                            if let Some(slot) = self.expression.get_mut(self.expression_len) {
+                                *slot = byte;
+                            }
+                            self.expression_len += 1;
+                        }
+                    }
+                    SumpCommand::CompileExpression => {
+                        // Compile the expression and reply with the error kind and column.
+                        let result = match self.expression_len {
+                            len if len > EXPR_LEN => Err(ParseError {
+                                kind: ParseErrorKind::TooLong,
+                                column: EXPR_LEN + 1,
+                            }),
+                            len => expr::compile(&self.expression[..len], self.trigger),
+                        };
+                        self.expression_len = 0;
+                        let reply = match result {
+                            Ok(trigger) => {
+                                self.trigger = trigger;
+                                [0; 3]
+                            }
+                            Err(err) => {
+                                let column = (err.column as u16).to_le_bytes();
+                                [err.kind as u8, column[0], column[1]]
+                            }
+                        };
+                        self.serial.write(&reply).ok();
+                    }
+                    SumpCommand::SetStreaming(flags) => {
+                        // Select streaming for the following arm commands, bit 0 of flags enables it.
+                        self.sampler.set_streaming(flags & 1 == 1);
+                    }
+                    SumpCommand::SetTransitions(flags) => {
+                        // Select transition sampling, bit 0 of flags enables it, bit 1 sends the records.
+                        self.sampler.set_transitions(flags);
+                    }
+                    SumpCommand::SetTransitionSpan(span) => {
+                        // Set the span of the following transition captures.
+                        self.sampler.set_span(span);
+                    }
+                    SumpCommand::SetCompression(flags) => {
+                        // Select compression for the following captures, bit 0 of flags enables it.
+                        self.sampler.set_compression(flags & 1 == 1);
+                    }
+                    SumpCommand::GetCompression => {
+                        // Send the packing of the last capture with the bytes captured and stored.
+                        let (packing, raw, stored) = self.sampler.compression();
+                        self.serial.write(&[packing as u8]).ok();
+                        self.serial.write(&raw.to_le_bytes()).ok();
+                        self.serial.write(&stored.to_le_bytes()).ok();
+                    }
+                    SumpCommand::SetChannels(channels) => {
+                        // Set the number of channels the following captures pack.
+                        self.sampler.set_channels(channels);
+                    }
+                    SumpCommand::SetSegments(count) => {
+                        // Set the number of segments the following captures fill.
+                        self.sampler.set_segments(count as usize);
+                    }
+                    SumpCommand::SetRepeat(flags) => {
+                        // Select repeat mode, bit 0 of flags re-arms after each upload until reset.
+                        self.repeat = flags & 1 == 1;
+                    }
+                    SumpCommand::SetSysClock(clock) => {
+                        // Reprogram the system clock unless a capture is running, reply 0 on success.
+                        let running =
+                            matches!(self.status, CaptureStatus::Armed | CaptureStatus::Streaming);
+                        let done = !running && self.sys_clock.set(clock);
+                        self.serial.write(&[!done as u8]).ok();
+                    }
+                    SumpCommand::SetSampleRate(rate) => {
+                        // Set the sampling rate and reply with the closest one the clocks achieve.
+                        self.sampler.set_rate(rate);
+                        let plan = plan(self.sampler.rate(), self.sys_clock.max_rate());
+                        self.serial.write(&plan.rate().to_le_bytes()).ok();
+                    }
+                    SumpCommand::SetAnalogInputs(inputs) => {
+                        // Select the analog inputs, bit 0 for GPIO 26, zero disables the analog capture.
+                        self.sampler.set_analog_inputs(inputs);
+                    }
+                    SumpCommand::SetAnalogRate(rate) => {
+                        // Set the conversion rate and reply with the one the ADC achieves.
+                        let rate = self.sampler.set_analog_rate(rate);
+                        self.serial.write(&rate.to_le_bytes()).ok();
+                    }
+                    SumpCommand::GetAnalog => {
+                        // Send the analog samples of the last capture, newest first.
+                        self.sampler.upload_analog(&mut self.serial);
+                    }
+                    SumpCommand::SetSyncBypass(flags) => {
+                        // Select the bypass, bit 0 of flags skips the synchronizers from the next capture.
+                        self.sampler.set_sync_bypass(flags & 1 == 1);
+                    }
+                    SumpCommand::GetDepth => {
+                        // Send the depth in samples for 16, 8, 4, 2 and 1 bits per sample.
+                        for width in [16, 8, 4, 2, 1] {
+                            self.serial
+                                .write(&self.sampler.depth(width).to_le_bytes())
+                                .ok();
+                        }
+                    }
+                    SumpCommand::GetStreamRate => {
+                        // Send the sustained streaming rate for the enabled channel groups.
+                        self.serial
+                            .write(&self.sampler.stream_rate().to_le_bytes())
+                            .ok();
+                    }
+                    SumpCommand::GetStatus => {
+                        // Send the status of the last capture, bit 7 flags a synchronizer bypass.
+                        let (_, bypassed) = self.sampler.sync_bypass();
+                        let status = self.status as u8 | (bypassed as u8) << 7;
+                        self.serial.write(&[status]).ok();
+                    }
                     SumpCommand::GetId => {
                         // Send the device ID over the serial port.
                         self.serial.write(b"1ALS").ok();
@@ -136,12 +474,24 @@ impl LogicAnalyzer {
                         // Send metadata information over the serial port.
                         self.serial.write(&[0x01]).ok();
                         self.serial.write(b"uLA: Micro Logic Analyzer").ok();
+                        if self.sys_clock.overclocked() {
+                            // Flag clocks beyond the rated maximum in the device name.
+                            self.serial.write(b" (overclocked)").ok();
+                        }
+                        if self.sampler.sync_bypass().0 {
+                            // Flag unsynchronized inputs, they may sample a metastable level.
+                            self.serial.write(b" (sync bypass)").ok();
+                        }
                         self.serial.write(&[0x00, 0x20]).ok();
                         self.serial.write(&PROBES.to_be_bytes()).ok();
                         self.serial.write(&[0x21]).ok();
-                        self.serial.write(&(SAMPLE_MEMORY).to_be_bytes()).ok();
+                        self.serial
+                            .write(&self.sampler.memory_size().to_be_bytes())
+                            .ok();
                         self.serial.write(&[0x23]).ok();
-                        self.serial.write(&SAMPLE_RATE.to_be_bytes()).ok();
+                        self.serial
+                            .write(&self.sys_clock.max_rate().to_be_bytes())
+                            .ok();
                         self.serial
                             .write(&[0x24, 0x00, 0x00, 0x00, 0x02, 0x00])
                             .ok();
@@ -152,6 +502,40 @@ impl LogicAnalyzer {
                 }
             }
         }
+        self.sampler.upload(&mut self.serial);
+
+        // In repeat mode the next capture starts as soon as the last one is sent.
+        let captured = matches!(
+            self.status,
+            CaptureStatus::Triggered | CaptureStatus::AutoTriggered
+        );
+        if self.repeat && captured && !self.sampler.uploading() {
+            self.arm();
+        }
+    }
+
+    /// Activates the status LED and starts the sampler with the current trigger.
+    fn arm(&mut self) {
+        if !self.sampler.accepts(&self.trigger) {
+            // Nothing is started, report the trigger as too large instead.
+            self.status = CaptureStatus::Rejected;
+            self.auto_trigger_deadline = None;
+            return;
+        }
+        // Retune the system clock so the sampling rate divides it evenly.
+        self.sys_clock.tune(self.sampler.rate());
+        self.sampler.start(self.trigger);
+        self.status_led.set_high().unwrap();
+        if self.sampler.streaming() {
+            self.status = CaptureStatus::Streaming;
+            self.auto_trigger_deadline = None;
+        } else {
+            self.status = CaptureStatus::Armed;
+            self.auto_trigger_deadline = match self.auto_trigger {
+                0 => None,
+                timeout => Some(Mono::now() + (timeout as u64).millis()),
+            };
+        }
     }
 
     /// Parses incoming serial data to identify and construct Sump commands.
@@ -186,22 +570,49 @@ impl LogicAnalyzer {
                         self.drain_rx(1);
                         Some(SumpCommand::GetMeta)
                     }
+                    0x20 => {
+                        // GetStatus command (μLA extension).
+                        self.drain_rx(1);
+                        Some(SumpCommand::GetStatus)
+                    }
+                    0x21 => {
+                        // GetStreamRate command (μLA extension).
+                        self.drain_rx(1);
+                        Some(SumpCommand::GetStreamRate)
+                    }
+                    0x22 => {
+                        // GetCompression command (μLA extension).
+                        self.drain_rx(1);
+                        Some(SumpCommand::GetCompression)
+                    }
+                    0x23 => {
+                        // GetDepth command (μLA extension).
+                        self.drain_rx(1);
+                        Some(SumpCommand::GetDepth)
+                    }
+                    0x24 => {
+                        // GetAnalog command (μLA extension).
+                        self.drain_rx(1);
+                        Some(SumpCommand::GetAnalog)
+                    }
                     cmd if self.needle > 4 => {
                         // Handle more complex commands that require additional bytes.
                         match cmd {
                             0x80 => {
-                                // SetDivisor command with a 4-byte prescaler.
+                                // SetDivisor command with a 4-byte prescaler, only the low 24 bits count.
                                 let prescaler =
                                     u32::from_le_bytes(self.scratch[1..5].try_into().unwrap());
                                 self.drain_rx(5);
-                                Some(SumpCommand::SetDivisor(prescaler as _))
+                                Some(SumpCommand::SetDivisor(prescaler & 0xff_ffff))
                             }
                             0x81 => {
-                                // SetReadCount command with a 2-byte sample count.
+                                // SetReadCount command with 2-byte sample and delay counts.
                                 let samples =
                                     u16::from_le_bytes(self.scratch[1..3].try_into().unwrap());
+                                let delay =
+                                    u16::from_le_bytes(self.scratch[3..5].try_into().unwrap());
                                 self.drain_rx(5);
-                                Some(SumpCommand::SetReadCount(samples as _))
+                                Some(SumpCommand::SetReadCount(samples as _, delay as _))
                             }
                             0x82 => {
                                 // SetFlags command with a single byte of flags.
@@ -209,6 +620,180 @@ impl LogicAnalyzer {
                                 self.drain_rx(5);
                                 Some(SumpCommand::SetFlags(flags))
                             }
+                            0xa0 => {
+                                // SetAutoTrigger command with a 4-byte timeout (μLA extension).
+                                let timeout =
+                                    u32::from_le_bytes(self.scratch[1..5].try_into().unwrap());
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetAutoTrigger(timeout))
+                            }
+                            0xa1 => {
+                                // SetTriggerOutput command with pin and flags bytes (μLA extension).
+                                let pin = self.scratch[1];
+                                let flags = self.scratch[2];
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetTriggerOutput(pin, flags))
+                            }
+                            0xa2 => {
+                                // SetTriggerExternal command with stage, pin and flags bytes (μLA extension).
+                                let stage = self.scratch[1];
+                                let pin = self.scratch[2];
+                                let flags = self.scratch[3];
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetTriggerExternal(stage, pin, flags))
+                            }
+                            0xa3 => {
+                                // SetTriggerGroup command with stage and group bytes (μLA extension).
+                                let stage = self.scratch[1];
+                                let group = self.scratch[2];
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetTriggerGroup(stage, group))
+                            }
+                            0xa4 => {
+                                // SetProtocolTrigger command with kind and parameter bytes (μLA extension).
+                                let kind = self.scratch[1];
+                                let params = self.scratch[2..5].try_into().unwrap();
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetProtocolTrigger(kind, params))
+                            }
+                            0xa5 => {
+                                // SetProtocolRate command with a 4-byte bit rate (μLA extension).
+                                let rate =
+                                    u32::from_le_bytes(self.scratch[1..5].try_into().unwrap());
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetProtocolRate(rate))
+                            }
+                            0xa6 => {
+                                // SetProtocolValue command with a 4-byte value (μLA extension).
+                                let value =
+                                    u32::from_le_bytes(self.scratch[1..5].try_into().unwrap());
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetProtocolValue(value))
+                            }
+                            0xa7 => {
+                                // SetProtocolMask command with a 4-byte mask (μLA extension).
+                                let mask =
+                                    u32::from_le_bytes(self.scratch[1..5].try_into().unwrap());
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetProtocolMask(mask))
+                            }
+                            0xa8 => {
+                                // SetTriggerWindow command with stage and 3-byte window (μLA extension).
+                                let stage = self.scratch[1];
+                                let mut window = [0; 4];
+                                window[..3].copy_from_slice(&self.scratch[2..5]);
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetTriggerWindow(
+                                    stage,
+                                    u32::from_le_bytes(window),
+                                ))
+                            }
+                            0xa9 => {
+                                // AppendExpression command with 4 expression bytes (μLA extension).
+                                let bytes = self.scratch[1..5].try_into().unwrap();
+                                self.drain_rx(5);
+                                Some(SumpCommand::AppendExpression(bytes))
+                            }
+                            0xaa => {
+                                // CompileExpression command, payload is ignored (μLA extension).
+                                self.drain_rx(5);
+                                Some(SumpCommand::CompileExpression)
+                            }
+                            0xab => {
+                                // SetTriggerRange command with stage, channel, width and flags bytes (μLA extension).
+                                let stage = self.scratch[1];
+                                let channel = self.scratch[2];
+                                let width = self.scratch[3];
+                                let flags = self.scratch[4];
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetTriggerRange(stage, channel, width, flags))
+                            }
+                            0xac => {
+                                // SetStreaming command with a single byte of flags (μLA extension).
+                                let flags = self.scratch[1];
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetStreaming(flags))
+                            }
+                            0xad => {
+                                // SetTransitions command with a single byte of flags (μLA extension).
+                                let flags = self.scratch[1];
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetTransitions(flags))
+                            }
+                            0xae => {
+                                // SetTransitionSpan command with a 4-byte sample count (μLA extension).
+                                let span =
+                                    u32::from_le_bytes(self.scratch[1..5].try_into().unwrap());
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetTransitionSpan(span))
+                            }
+                            0xaf => {
+                                // SetCompression command with a single byte of flags (μLA extension).
+                                let flags = self.scratch[1];
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetCompression(flags))
+                            }
+                            0xb0..=0xb3 => {
+                                // SetTriggerRangeBounds command for different stages (μLA extension).
+                                let stage = self.scratch[0] - 0xb0;
+                                let min =
+                                    u16::from_le_bytes(self.scratch[1..3].try_into().unwrap());
+                                let max =
+                                    u16::from_le_bytes(self.scratch[3..5].try_into().unwrap());
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetTriggerRangeBounds(stage, min, max))
+                            }
+                            0xb4 => {
+                                // SetChannels command with a single byte channel count (μLA extension).
+                                let channels = self.scratch[1];
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetChannels(channels))
+                            }
+                            0xb5 => {
+                                // SetSegments command with a single byte segment count (μLA extension).
+                                let count = self.scratch[1];
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetSegments(count))
+                            }
+                            0xb6 => {
+                                // SetRepeat command with a single byte of flags (μLA extension).
+                                let flags = self.scratch[1];
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetRepeat(flags))
+                            }
+                            0xb7 => {
+                                // SetSysClock command with a 4-byte frequency in Hertz (μLA extension).
+                                let clock =
+                                    u32::from_le_bytes(self.scratch[1..5].try_into().unwrap());
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetSysClock(clock))
+                            }
+                            0xb8 => {
+                                // SetSampleRate command with a 4-byte rate in Hertz (μLA extension).
+                                let rate =
+                                    u32::from_le_bytes(self.scratch[1..5].try_into().unwrap());
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetSampleRate(rate))
+                            }
+                            0xb9 => {
+                                // SetAnalogInputs command with a single byte input mask (μLA extension).
+                                let inputs = self.scratch[1];
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetAnalogInputs(inputs))
+                            }
+                            0xba => {
+                                // SetAnalogRate command with a 4-byte rate in Hertz (μLA extension).
+                                let rate =
+                                    u32::from_le_bytes(self.scratch[1..5].try_into().unwrap());
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetAnalogRate(rate))
+                            }
+                            0xbb => {
+                                // SetSyncBypass command with a single byte of flags (μLA extension).
+                                let flags = self.scratch[1];
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetSyncBypass(flags))
+                            }
                             0xc0 | 0xc4 | 0xc8 | 0xcc => {
                                 // SetTriggerMask command for different stages.
                                 let stage = (self.scratch[0] - 0xc0) / 4;
@@ -233,6 +818,14 @@ impl LogicAnalyzer {
                                 self.drain_rx(5);
                                 Some(SumpCommand::SetTriggerDelay(stage, delay as _))
                             }
+                            0xc3 | 0xc7 | 0xcb | 0xcf => {
+                                // SetTriggerCount command for different stages (μLA extension).
+                                let stage = (self.scratch[0] - 0xc3) / 4;
+                                let count =
+                                    u16::from_le_bytes(self.scratch[1..3].try_into().unwrap());
+                                self.drain_rx(5);
+                                Some(SumpCommand::SetTriggerCount(stage, count as _))
+                            }
                             _ => {
                                 // Unknown command, drain one byte and ignore.
                                 self.drain_rx(1);
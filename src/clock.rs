@@ -0,0 +1,468 @@
+use crate::*;
+use core::sync::atomic::{AtomicU32, Ordering};
+use pac::clocks::clk_sys_ctrl::AUXSRC_A;
+use pac::vreg_and_chip_reset::vreg::VSEL_A;
+
+/// Reference clock SUMP hosts compute the sampling divisor against, in Hertz.
+pub const SUMP_CLOCK: u32 = 100_000_000;
+/// Highest system clock the RP2040 is rated for, faster clocks are overclocked.
+pub const RATED_CLOCK: u32 = 133_000_000;
+/// Lowest system clock the rate planner picks, USB needs the system at least as fast as itself.
+pub const MIN_CLOCK: u32 = 48_000_000;
+
+/// System clock selected at build time, in Hertz.
+pub const DEFAULT_CLOCK: u32 = if cfg!(feature = "sysclk-250mhz") {
+    250_000_000
+} else if cfg!(feature = "sysclk-200mhz") {
+    200_000_000
+} else if cfg!(feature = "sysclk-133mhz") {
+    133_000_000
+} else if cfg!(feature = "sysclk-125mhz") {
+    125_000_000
+} else {
+    100_000_000
+};
+
+/// Current system clock in Hertz, the capture takes at most one sample per clock.
+static SYS_CLOCK: AtomicU32 = AtomicU32::new(DEFAULT_CLOCK);
+
+/// Struct representing a PLL_SYS configuration with the reference divider at 1.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct PllConfig {
+    /// Resulting system clock in Hertz.
+    clock: u32,
+    /// Feedback divider, the VCO runs at this multiple of the crystal.
+    fbdiv: u16,
+    /// First post divider.
+    postdiv1: u8,
+    /// Second post divider, not larger than the first.
+    postdiv2: u8,
+}
+
+/// Supported highest system clocks, the VCO stays within 750-1600 MHz.
+const PLL_CONFIGS: [PllConfig; 5] = [
+    PllConfig {
+        clock: 100_000_000,
+        fbdiv: 125,
+        postdiv1: 5,
+        postdiv2: 3,
+    },
+    PllConfig {
+        clock: 125_000_000,
+        fbdiv: 125,
+        postdiv1: 6,
+        postdiv2: 2,
+    },
+    PllConfig {
+        clock: 133_000_000,
+        fbdiv: 133,
+        postdiv1: 6,
+        postdiv2: 2,
+    },
+    PllConfig {
+        clock: 200_000_000,
+        fbdiv: 100,
+        postdiv1: 6,
+        postdiv2: 1,
+    },
+    PllConfig {
+        clock: 250_000_000,
+        fbdiv: 125,
+        postdiv1: 6,
+        postdiv2: 1,
+    },
+];
+
+/// Returns the current system clock, which is also the highest sampling rate.
+///
+/// # Returns
+///
+/// System clock in Hertz.
+pub fn sample_rate() -> u32 {
+    SYS_CLOCK.load(Ordering::Relaxed)
+}
+
+/// Returns the core voltage a system clock needs.
+///
+/// # Arguments
+///
+/// * `clock` - System clock in Hertz.
+fn core_voltage(clock: u32) -> VSEL_A {
+    match clock {
+        clock if clock > 200_000_000 => VSEL_A::VOLTAGE1_20,
+        clock if clock > RATED_CLOCK => VSEL_A::VOLTAGE1_15,
+        _ => VSEL_A::VOLTAGE1_10,
+    }
+}
+
+/// Struct representing the system clock, driven by PLL_SYS.
+///
+/// USB runs from PLL_USB and the monotonic timer from the reference clock,
+/// so both keep their timing when the system clock changes.
+pub struct SysClock {
+    /// Voltage regulator, raised for overclocked system clocks.
+    vreg: pac::VREG_AND_CHIP_RESET,
+    /// Highest system clock the rate planner may pick, in Hertz.
+    ceiling: u32,
+    /// PLL configuration currently running.
+    pll: PllConfig,
+}
+
+impl SysClock {
+    /// Creates a new instance of the system clock, running at the build time default.
+    ///
+    /// # Arguments
+    ///
+    /// * `vreg` - Voltage regulator of the core.
+    ///
+    /// # Returns
+    ///
+    /// A new `SysClock` instance.
+    pub fn new(vreg: pac::VREG_AND_CHIP_RESET) -> Self {
+        let pll = PLL_CONFIGS[0];
+        let mut clock = Self {
+            vreg,
+            ceiling: pll.clock,
+            pll,
+        };
+        clock.set(DEFAULT_CLOCK);
+        clock
+    }
+
+    /// Returns the highest system clock, which is also the highest sampling rate.
+    ///
+    /// # Returns
+    ///
+    /// Highest system clock in Hertz.
+    pub fn max_rate(&self) -> u32 {
+        self.ceiling
+    }
+
+    /// Checks whether the highest system clock is above the rated maximum.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the system clock may be overclocked.
+    pub fn overclocked(&self) -> bool {
+        self.ceiling > RATED_CLOCK
+    }
+
+    /// Sets the highest system clock and switches to it.
+    ///
+    /// Captures must not be running, their state machines are configured
+    /// for the previous clock.
+    ///
+    /// # Arguments
+    ///
+    /// * `clock` - System clock in Hertz, one of the supported ones.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the clock isn't supported.
+    pub fn set(&mut self, clock: u32) -> bool {
+        let Some(config) = PLL_CONFIGS.iter().find(|config| config.clock == clock) else {
+            return false;
+        };
+        self.ceiling = clock;
+        self.apply(*config);
+        true
+    }
+
+    /// Retunes the system clock for a sampling rate, see [`plan`].
+    ///
+    /// Captures then derive their divisor from the new clock with [`clock_divisor`].
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - Sampling rate in Hertz as a numerator and denominator.
+    pub fn tune(&mut self, rate: (u64, u64)) {
+        let plan = plan(rate, self.ceiling);
+        if plan.pll.clock != self.pll.clock {
+            self.apply(plan.pll);
+        }
+    }
+
+    /// Reprograms PLL_SYS, the system runs from PLL_USB meanwhile.
+    ///
+    /// Retuning happens from the USB interrupt with the bus live, the system
+    /// clock stays as fast as USB while PLL_SYS relocks. The reference clock
+    /// only covers the few cycles of each switch of the auxiliary mux.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - PLL configuration of the new system clock.
+    fn apply(&mut self, config: PllConfig) {
+        let clock = config.clock;
+        // Raise the voltage before speeding up, lower it after slowing down.
+        let voltage = core_voltage(clock.max(sample_rate()));
+        hal::vreg::set_voltage(&mut self.vreg, voltage);
+        // Let the regulator settle.
+        cortex_m::asm::delay(10_000);
+
+        let pll = unsafe { &*pac::PLL_SYS::ptr() };
+        // Run from PLL_USB while PLL_SYS relocks.
+        select_aux(AUXSRC_A::CLKSRC_PLL_USB);
+
+        pll.pwr()
+            .modify(|_, w| w.pd().set_bit().vcopd().set_bit().postdivpd().set_bit());
+        pll.fbdiv_int()
+            .write(|w| unsafe { w.fbdiv_int().bits(config.fbdiv) });
+        pll.pwr()
+            .modify(|_, w| w.pd().clear_bit().vcopd().clear_bit());
+        while pll.cs().read().lock().bit_is_clear() {}
+        pll.prim().write(|w| unsafe {
+            w.postdiv1()
+                .bits(config.postdiv1)
+                .postdiv2()
+                .bits(config.postdiv2)
+        });
+        pll.pwr().modify(|_, w| w.postdivpd().clear_bit());
+
+        select_aux(AUXSRC_A::CLKSRC_PLL_SYS);
+
+        hal::vreg::set_voltage(&mut self.vreg, core_voltage(clock));
+        SYS_CLOCK.store(clock, Ordering::Relaxed);
+        self.pll = config;
+    }
+}
+
+/// Switches the system clock to another auxiliary source.
+///
+/// The auxiliary mux isn't glitchless, the system runs from the reference
+/// clock while it switches.
+///
+/// # Arguments
+///
+/// * `source` - Auxiliary source to run the system clock from.
+fn select_aux(source: AUXSRC_A) {
+    let clocks = unsafe { &*pac::CLOCKS::ptr() };
+    clocks.clk_sys_ctrl().modify(|_, w| w.src().clk_ref());
+    while clocks.clk_sys_selected().read().bits() != 1 {}
+    clocks
+        .clk_sys_ctrl()
+        .modify(|_, w| w.auxsrc().variant(source));
+    clocks
+        .clk_sys_ctrl()
+        .modify(|_, w| w.src().clksrc_clk_sys_aux());
+    while clocks.clk_sys_selected().read().bits() != 2 {}
+}
+
+/// Struct representing the clocks chosen for a sampling rate.
+#[cfg_attr(test, derive(Debug))]
+pub struct RatePlan {
+    /// PLL configuration of the system clock.
+    pub pll: PllConfig,
+    /// Clock divisor of the capture state machine.
+    pub divisor: u16,
+}
+
+impl RatePlan {
+    /// Returns the sampling rate the plan achieves.
+    ///
+    /// # Returns
+    ///
+    /// Sampling rate in Hertz, rounded down.
+    pub fn rate(&self) -> u32 {
+        self.pll.clock / self.divisor as u32
+    }
+}
+
+/// Picks the system clock and state machine divisor closest to a sampling rate.
+///
+/// Every PLL_SYS configuration giving a whole number of Hertz between
+/// `MIN_CLOCK` and the ceiling is paired with its nearest integer divisor.
+/// The closest rate wins, ties go to the fastest system clock so rates the
+/// ceiling divides evenly keep it, then to the fastest VCO for less jitter.
+///
+/// # Arguments
+///
+/// * `rate` - Sampling rate in Hertz as a numerator and denominator, so
+///   rates like 100 MHz / 3 are exact.
+/// * `ceiling` - Highest system clock in Hertz.
+///
+/// # Returns
+///
+/// The rate plan, the slowest or fastest one for rates out of reach.
+pub fn plan(rate: (u64, u64), ceiling: u32) -> RatePlan {
+    let (num, den) = rate;
+    let mut best = RatePlan {
+        pll: PLL_CONFIGS[0],
+        divisor: divisor_for(PLL_CONFIGS[0].clock, rate),
+    };
+    // Rate error as a fraction, compared by cross multiplication. The first one is infinite.
+    let mut best_error = (1, 0);
+    for fbdiv in (16..=320_u16).rev() {
+        let vco = XTAL_FREQ_HZ as u64 * fbdiv as u64;
+        if !(750_000_000..=1_600_000_000).contains(&vco) {
+            continue;
+        }
+        for postdiv1 in 1..=7_u8 {
+            for postdiv2 in 1..=postdiv1 {
+                let post = postdiv1 as u64 * postdiv2 as u64;
+                let clock = vco / post;
+                if !vco.is_multiple_of(post) || clock > ceiling as u64 || clock < MIN_CLOCK as u64 {
+                    continue;
+                }
+                let divisor = divisor_for(clock as u32, rate);
+                let error = (
+                    (clock as u128 * den as u128).abs_diff(num as u128 * divisor as u128),
+                    divisor as u128 * den as u128,
+                );
+                let closer = error.0 * best_error.1 < best_error.0 * error.1;
+                let tie = error.0 * best_error.1 == best_error.0 * error.1;
+                if closer || tie && clock as u32 > best.pll.clock {
+                    best_error = error;
+                    best = RatePlan {
+                        pll: PllConfig {
+                            clock: clock as u32,
+                            fbdiv,
+                            postdiv1,
+                            postdiv2,
+                        },
+                        divisor,
+                    };
+                }
+            }
+        }
+    }
+    best
+}
+
+/// Returns the integer clock divisor nearest to a sampling rate.
+///
+/// # Arguments
+///
+/// * `clock` - System clock in Hertz.
+/// * `rate` - Sampling rate in Hertz as a numerator and denominator.
+///
+/// # Returns
+///
+/// Clock divisor of the capture state machine.
+fn divisor_for(clock: u32, rate: (u64, u64)) -> u16 {
+    let (num, den) = rate;
+    let clocks = clock as u64 * den;
+    ((clocks + num / 2) / num.max(1)).clamp(1, u16::MAX as u64) as u16
+}
+
+/// Converts a sampling rate into a state machine clock divisor at the current system clock.
+///
+/// # Arguments
+///
+/// * `rate` - Sampling rate in Hertz as a numerator and denominator.
+///
+/// # Returns
+///
+/// Integer clock divisor of the capture state machine, rounded to the nearest.
+pub fn clock_divisor(rate: (u64, u64)) -> u16 {
+    divisor_for(sample_rate(), rate)
+}
+
+/// Converts a SUMP divider into a sampling rate.
+///
+/// # Arguments
+///
+/// * `divider` - 24-bit divider of the SUMP reference clock.
+/// * `demux` - Whether the divider counts against the doubled reference clock.
+///
+/// # Returns
+///
+/// Sampling rate in Hertz as a numerator and denominator.
+pub fn sump_rate(divider: u32, demux: bool) -> (u64, u64) {
+    (SUMP_CLOCK as u64 * (demux as u64 + 1), divider as u64 + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sampling rates sigrok offers, as numerators and denominators in Hertz.
+    const SIGROK_RATES: [(u64, u64); 38] = [
+        (10, 1),
+        (20, 1),
+        (50, 1),
+        (100, 1),
+        (200, 1),
+        (500, 1),
+        (1_000, 1),
+        (2_000, 1),
+        (5_000, 1),
+        (10_000, 1),
+        (20_000, 1),
+        (25_000, 1),
+        (50_000, 1),
+        (100_000, 1),
+        (200_000, 1),
+        (250_000, 1),
+        (500_000, 1),
+        (1_000_000, 1),
+        (2_000_000, 1),
+        (2_500_000, 1),
+        (3_000_000, 1),
+        (4_000_000, 1),
+        (5_000_000, 1),
+        (6_000_000, 1),
+        (8_000_000, 1),
+        (10_000_000, 1),
+        (12_000_000, 1),
+        (16_000_000, 1),
+        (20_000_000, 1),
+        (24_000_000, 1),
+        (25_000_000, 1),
+        (100_000_000, 3),
+        (40_000_000, 1),
+        (48_000_000, 1),
+        (50_000_000, 1),
+        (200_000_000, 3),
+        (100_000_000, 1),
+        (200_000_000, 1),
+    ];
+
+    fn exact(plan: &RatePlan, (num, den): (u64, u64)) -> bool {
+        plan.pll.clock as u64 * den == num * plan.divisor as u64
+    }
+
+    #[test]
+    fn sigrok_rates_are_exact() {
+        for rate in SIGROK_RATES {
+            let plan = plan(rate, 250_000_000);
+            let vco = XTAL_FREQ_HZ as u64 * plan.pll.fbdiv as u64;
+            assert!((750_000_000..=1_600_000_000).contains(&vco));
+            assert_eq!(
+                vco,
+                plan.pll.clock as u64 * (plan.pll.postdiv1 * plan.pll.postdiv2) as u64
+            );
+            assert!(plan.pll.postdiv2 <= plan.pll.postdiv1);
+            // The divisor can't slow a 48 MHz system clock below 732 Hz.
+            match rate.0 / rate.1 {
+                0..1_000 => assert_eq!((plan.pll.clock, plan.divisor), (MIN_CLOCK, u16::MAX)),
+                _ => assert!(exact(&plan, rate), "{rate:?} planned as {plan:?}"),
+            }
+        }
+        assert_eq!(plan((100_000_000, 3), 250_000_000).rate(), 33_333_333);
+    }
+
+    #[test]
+    fn sump_rates_keep_the_default_clock() {
+        for divisor in 1..=1_000 {
+            let rate = (SUMP_CLOCK as u64, divisor);
+            let plan = plan(rate, 100_000_000);
+            assert_eq!((plan.pll, plan.divisor), (PLL_CONFIGS[0], divisor as u16));
+        }
+    }
+
+    #[test]
+    fn wide_sump_dividers_keep_their_rate() {
+        // 100 MHz / 100_000, the divider no longer fits 16 bits.
+        assert_eq!(plan(sump_rate(99_999, false), 100_000_000).rate(), 1_000);
+        // Dividers beyond the hardware limit clamp to the slowest rate.
+        let slowest = plan(sump_rate(0xff_ffff, true), 100_000_000);
+        assert_eq!((slowest.pll.clock, slowest.divisor), (MIN_CLOCK, u16::MAX));
+    }
+
+    #[test]
+    fn rates_beyond_the_ceiling_run_at_it() {
+        let plan = plan((200_000_000, 1), 133_000_000);
+        assert_eq!((plan.pll.clock, plan.divisor), (133_000_000, 1));
+    }
+}
@@ -0,0 +1,547 @@
+use crate::*;
+use core::sync::atomic::{compiler_fence, Ordering};
+use hal::multicore::{Multicore, Stack};
+use hal::sio::SioFifo;
+
+/// Number of words core1 compresses at a time.
+pub const BLOCK: usize = 256;
+/// Number of words in the staging ring the DMA fills while compressing.
+pub const STAGING: usize = 2048;
+/// Lowest clock divisor core1 keeps up with, it takes that many system clocks per sample.
+pub const COMPRESS_DIVISOR: u16 = 20;
+
+/// Frame length flag marking a run-length encoded payload.
+const RLE: u32 = 1 << 31;
+/// FIFO message starting a compression session.
+const START: u32 = 1;
+/// FIFO message flushing the staging ring and ending the session.
+const STOP: u32 = 2;
+
+/// Stack of the compression loop on core1.
+static CORE1_STACK: Stack<512> = Stack::new();
+
+/// Type alias for the peripherals needed to start core1.
+pub type Core1 = (pac::PSM, pac::PPB, SioFifo);
+
+/// Staging ring, aligned to its size so the DMA can wrap around it.
+#[repr(C, align(8192))]
+pub struct Staging([u32; STAGING]);
+
+const _: () = assert!(core::mem::align_of::<Staging>() == 4 * STAGING);
+
+/// Enumeration of the ways the last capture was stored.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Packing {
+    /// Samples were stored as captured.
+    Raw = 0,
+    /// Core1 compressed the samples while capturing.
+    Compressed = 1,
+    /// Core1 fell behind, only the newest staged samples were kept.
+    Behind = 2,
+}
+
+/// Struct representing the circular store of compressed frames.
+///
+/// Each frame holds one block of staged words, either as runs of equal
+/// samples or as captured when runs don't save space. Its length goes before
+/// and after the payload, so frames can be walked in both directions. Once
+/// the store is full the oldest frames are dropped.
+pub struct Store<'a> {
+    /// Memory holding the frames.
+    memory: &'a mut [u32],
+    /// Index following the newest frame.
+    head: usize,
+    /// Index of the oldest frame.
+    tail: usize,
+    /// Number of words in use.
+    used: usize,
+    /// Number of words pushed.
+    raw: u32,
+    /// Number of words the pushed frames took.
+    stored: u32,
+}
+
+impl<'a> Store<'a> {
+    /// Creates an empty store.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory` - Memory holding the frames.
+    ///
+    /// # Returns
+    ///
+    /// A new `Store` instance.
+    pub fn new(memory: &'a mut [u32]) -> Self {
+        Self {
+            memory,
+            head: 0,
+            tail: 0,
+            used: 0,
+            raw: 0,
+            stored: 0,
+        }
+    }
+
+    /// Compresses a block of words into a new frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `block` - Words of two samples each, the older one in the low half.
+    pub fn push(&mut self, block: &[u32]) {
+        let runs = encode(block, |_| {});
+        let (len, rle) = match runs < block.len() {
+            true => (runs, RLE),
+            false => (block.len(), 0),
+        };
+        if len + 2 > self.memory.len() {
+            return;
+        }
+        // Drop the oldest frames to make room.
+        while self.used + len + 2 > self.memory.len() {
+            let dropped = (self.memory[self.tail] & !RLE) as usize + 2;
+            self.tail = (self.tail + dropped) % self.memory.len();
+            self.used -= dropped;
+        }
+
+        self.write(len as u32 | rle);
+        match rle {
+            RLE => {
+                encode(block, |run| self.write(run));
+            }
+            _ => block.iter().for_each(|word| self.write(*word)),
+        }
+        self.write(len as u32 | rle);
+        self.used += len + 2;
+        self.raw += block.len() as u32;
+        self.stored += len as u32 + 2;
+    }
+
+    /// Returns the position of the newest frame.
+    ///
+    /// # Returns
+    ///
+    /// The index following the newest frame and the number of words in use.
+    pub fn head(&self) -> (usize, usize) {
+        (self.head, self.used)
+    }
+
+    /// Returns the number of words pushed and the number of words they took.
+    pub fn ratio(&self) -> (u32, u32) {
+        (self.raw, self.stored)
+    }
+
+    /// Writes a word at the head.
+    fn write(&mut self, word: u32) {
+        self.memory[self.head] = word;
+        self.head = (self.head + 1) % self.memory.len();
+    }
+}
+
+/// Encodes the samples of a block as runs.
+///
+/// Each run holds the sample in the high half and the number of repeats
+/// minus one in the low half.
+///
+/// # Arguments
+///
+/// * `block` - Words of two samples each, the older one in the low half.
+/// * `emit` - Called with each run, oldest first.
+///
+/// # Returns
+///
+/// The number of runs.
+fn encode(block: &[u32], mut emit: impl FnMut(u32)) -> usize {
+    let mut runs = 0;
+    let mut run: Option<(u16, u32)> = None;
+    for sample in block
+        .iter()
+        .flat_map(|word| [*word as u16, (*word >> 16) as u16])
+    {
+        run = match run {
+            Some((value, count)) if value == sample && count < 0x10000 => Some((value, count + 1)),
+            Some((value, count)) => {
+                emit((value as u32) << 16 | (count - 1));
+                runs += 1;
+                Some((sample, 1))
+            }
+            None => Some((sample, 1)),
+        };
+    }
+    if let Some((value, count)) = run {
+        emit((value as u32) << 16 | (count - 1));
+        runs += 1;
+    }
+    runs
+}
+
+/// Struct walking the compressed frames back from the newest sample.
+pub struct Unpacker {
+    /// Index following the frame before the current one, offset by the
+    /// memory length so walking back never wraps below zero.
+    head: usize,
+    /// Number of words in frames not walked yet.
+    left: usize,
+    /// Index of the first payload word of the current frame.
+    start: usize,
+    /// Index following the next payload word to decode.
+    pos: usize,
+    /// Whether the current frame holds runs.
+    rle: bool,
+    /// Sample repeated by the current run.
+    value: u16,
+    /// Number of repeats left in the current run.
+    count: u32,
+    /// Older sample of the current word of a raw frame.
+    low: Option<u16>,
+}
+
+impl Unpacker {
+    /// Creates an unpacker starting at the newest frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `head` - Index following the newest frame and number of words in use.
+    /// * `len` - Length of the memory holding the frames.
+    ///
+    /// # Returns
+    ///
+    /// A new `Unpacker` instance.
+    pub fn new(head: (usize, usize), len: usize) -> Self {
+        Self {
+            head: head.0 + len,
+            left: head.1,
+            start: 0,
+            pos: 0,
+            rle: false,
+            value: 0,
+            count: 0,
+            low: None,
+        }
+    }
+
+    /// Decodes the next sample, newest first.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory` - Memory holding the frames.
+    ///
+    /// # Returns
+    ///
+    /// The sample, or `None` once all frames have been walked.
+    pub fn next(&mut self, memory: &[u32]) -> Option<u16> {
+        if let Some(low) = self.low.take() {
+            return Some(low);
+        }
+        while self.count == 0 {
+            if self.pos == self.start {
+                if self.left == 0 {
+                    // All frames walked, an exactly full store has no length word below the head.
+                    return None;
+                }
+                // Step back to the previous frame.
+                let len = (memory[(self.head - 1) % memory.len()] & !RLE) as usize;
+                if self.left < len + 2 {
+                    return None;
+                }
+                self.rle = memory[(self.head - 1) % memory.len()] & RLE != 0;
+                self.pos = self.head - 1;
+                self.start = self.pos - len;
+                self.head = self.start - 1;
+                self.left -= len + 2;
+                continue;
+            }
+            self.pos -= 1;
+            let word = memory[self.pos % memory.len()];
+            if !self.rle {
+                self.low = Some(word as u16);
+                return Some((word >> 16) as u16);
+            }
+            self.value = (word >> 16) as u16;
+            self.count = (word & 0xffff) + 1;
+        }
+        self.count -= 1;
+        Some(self.value)
+    }
+}
+
+/// Struct representing the compression loop running on core1.
+///
+/// While compressing, the data channel wraps around the staging ring with its
+/// transfer count running down from `u32::MAX`, which tells core1 how many
+/// words have been captured. Core1 compresses them block by block into the
+/// sample memory and hands the frames over once the capture has stopped.
+pub struct Compressor {
+    /// FIFO to core1.
+    fifo: SioFifo,
+    /// Staging ring the DMA fills.
+    staging: &'static Staging,
+    /// Whether core1 is compressing.
+    active: bool,
+    /// Packing of the last capture with the bytes captured and stored.
+    ratio: (Packing, u32, u32),
+}
+
+impl Compressor {
+    /// Starts the compression loop on core1.
+    ///
+    /// # Arguments
+    ///
+    /// * `core1` - Peripherals needed to start core1.
+    /// * `memory` - Sample memory the frames are stored in.
+    ///
+    /// # Returns
+    ///
+    /// A new `Compressor` instance.
+    pub fn new(core1: Core1, memory: &mut [u32]) -> Self {
+        let (mut psm, mut ppb, mut fifo) = core1;
+        let staging = singleton!(: Staging = Staging([0x00; STAGING])).unwrap();
+        let store = (memory.as_mut_ptr() as usize, memory.len());
+        let ring = staging.0.as_ptr() as usize;
+        {
+            let mut multicore = Multicore::new(&mut psm, &mut ppb, &mut fifo);
+            let core = &mut multicore.cores()[1];
+            core.spawn(CORE1_STACK.take().unwrap(), move || run(store, ring))
+                .unwrap();
+        }
+
+        Self {
+            fifo,
+            staging,
+            active: false,
+            ratio: (Packing::Raw, 0, 0),
+        }
+    }
+
+    /// Returns the address the data channel wraps around while compressing.
+    pub fn address(&self) -> u32 {
+        self.staging.0.as_ptr() as u32
+    }
+
+    /// Checks whether core1 is compressing.
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// Returns how the last capture was stored.
+    ///
+    /// # Returns
+    ///
+    /// The packing with the number of bytes captured and stored.
+    pub fn ratio(&self) -> (Packing, u32, u32) {
+        self.ratio
+    }
+
+    /// Starts compressing the staging ring, the data channel has to be
+    /// started first.
+    pub fn start(&mut self) {
+        self.fifo.drain();
+        self.fifo.write_blocking(START);
+        self.active = true;
+    }
+
+    /// Ends a session without keeping its frames.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory` - Sample memory the frames are stored in.
+    pub fn cancel(&mut self, memory: &mut [u32]) {
+        if self.active {
+            self.stop(memory);
+        }
+        self.ratio = (Packing::Raw, 0, 0);
+    }
+
+    /// Compresses what's left in the staging ring and ends the session, the
+    /// data channel has to be stopped first.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory` - Sample memory the frames are stored in.
+    ///
+    /// # Returns
+    ///
+    /// An unpacker starting at the newest sample.
+    pub fn stop(&mut self, memory: &mut [u32]) -> Unpacker {
+        self.fifo.write_blocking(STOP);
+        let mut reply = [0; 6];
+        for word in reply.iter_mut() {
+            *word = self.fifo.read_blocking();
+        }
+        self.active = false;
+        // Keep frame reads after core1 has written them.
+        compiler_fence(Ordering::SeqCst);
+
+        let [head, used, raw, stored, progress, behind] = reply;
+        if behind == 0 {
+            self.ratio = (Packing::Compressed, 4 * raw, 4 * stored);
+            return Unpacker::new((head as usize, used as usize), memory.len());
+        }
+
+        // Core1 fell behind, keep the samples still in the staging ring.
+        let mut store = Store::new(memory);
+        let mut block = [0; BLOCK];
+        let mut from = progress.saturating_sub(STAGING as u32);
+        while from < progress {
+            let len = BLOCK.min((progress - from) as usize);
+            stage(self.staging.0.as_ptr(), from, &mut block[..len]);
+            store.push(&block[..len]);
+            from += len as u32;
+        }
+        let (raw, stored) = store.ratio();
+        self.ratio = (Packing::Behind, 4 * raw, 4 * stored);
+        Unpacker::new(store.head(), memory.len())
+    }
+}
+
+/// Copies words out of the staging ring.
+///
+/// # Arguments
+///
+/// * `staging` - Start of the staging ring.
+/// * `from` - Number of words captured before the first one to copy.
+/// * `block` - Buffer the words are copied into.
+fn stage(staging: *const u32, from: u32, block: &mut [u32]) {
+    for (idx, word) in block.iter_mut().enumerate() {
+        let idx = (from as usize + idx) % STAGING;
+        *word = unsafe { staging.add(idx).read_volatile() };
+    }
+}
+
+/// Compression loop running on core1.
+///
+/// # Arguments
+///
+/// * `store` - Address and length in words of the sample memory.
+/// * `staging` - Address of the staging ring.
+fn run(store: (usize, usize), staging: usize) {
+    let peripherals = unsafe { pac::Peripherals::steal() };
+    let mut fifo = hal::Sio::new(peripherals.SIO).fifo;
+    let memory = unsafe { core::slice::from_raw_parts_mut(store.0 as *mut u32, store.1) };
+    let data = unsafe { &*pac::DMA::ptr() }.ch(CH11::id() as usize);
+    let captured = || u32::MAX - data.ch_trans_count().read().bits();
+    let mut block = [0; BLOCK];
+
+    loop {
+        if fifo.read_blocking() != START {
+            continue;
+        }
+        let mut store = Store::new(&mut *memory);
+        let mut consumed = 0;
+        let mut end = None;
+        let mut behind = false;
+        loop {
+            if end.is_none() && fifo.read() == Some(STOP) {
+                // The data channel has stopped, flush what it wrote.
+                end = Some(captured());
+            }
+            let ready = end.unwrap_or_else(captured).wrapping_sub(consumed) as usize;
+            behind |= ready > STAGING;
+            if behind || (end.is_some() && ready == 0) {
+                match end {
+                    Some(_) => break,
+                    None => continue,
+                }
+            }
+            let len = match ready {
+                ready if ready >= BLOCK => BLOCK,
+                ready if end.is_some() => ready,
+                _ => continue,
+            };
+            stage(staging as *const u32, consumed, &mut block[..len]);
+            // The block may have been overwritten while it was copied.
+            if captured().wrapping_sub(consumed) as usize > STAGING && end.is_none() {
+                behind = true;
+                continue;
+            }
+            store.push(&block[..len]);
+            consumed += len as u32;
+        }
+
+        let (head, used) = store.head();
+        let (raw, stored) = store.ratio();
+        for word in [
+            head as u32,
+            used as u32,
+            raw,
+            stored,
+            end.unwrap_or(consumed),
+            behind as u32,
+        ] {
+            fifo.write_blocking(word);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unpacks `count` samples newest first, `None` marks running out.
+    fn unpack(store: &Store, count: usize) -> Vec<Option<u16>> {
+        let mut unpacker = Unpacker::new(store.head(), store.memory.len());
+        (0..count)
+            .map(|_| unpacker.next(&store.memory[..]))
+            .collect()
+    }
+
+    #[test]
+    fn runs_round_trip_newest_first() {
+        let mut memory = [0; 64];
+        let mut store = Store::new(&mut memory);
+        store.push(&[0x0001_0001, 0x0002_0001, 0x0002_0002]);
+        store.push(&[0x0003_0003]);
+
+        let samples = unpack(&store, 9);
+        let expected = [3, 3, 2, 2, 2, 1, 1, 1].map(Some);
+        assert_eq!(samples[..8], expected);
+        assert_eq!(samples[8], None);
+        // Two runs, then a single word that doesn't compress, each frame adding two length words.
+        assert_eq!(store.ratio(), (4, 7));
+    }
+
+    #[test]
+    fn incompressible_blocks_are_stored_raw() {
+        let mut memory = [0; 64];
+        let mut store = Store::new(&mut memory);
+        let block: Vec<u32> = (0..8)
+            .map(|idx| ((2 * idx + 1) << 16) | (2 * idx))
+            .collect();
+        store.push(&block);
+
+        let samples = unpack(&store, 17);
+        let expected: Vec<_> = (0..16).rev().map(Some).collect();
+        assert_eq!(samples[..16], expected);
+        assert_eq!(samples[16], None);
+        assert_eq!(store.ratio(), (8, 10));
+    }
+
+    #[test]
+    fn full_store_drops_oldest_frames() {
+        let mut memory = [0; 8];
+        let mut store = Store::new(&mut memory);
+        for value in 1..=4 {
+            store.push(&[value << 16 | value; 4]);
+        }
+
+        // Frames take three words, only the newest two fit.
+        let samples = unpack(&store, 17);
+        assert!(samples[..8].iter().all(|sample| *sample == Some(4)));
+        assert!(samples[8..16].iter().all(|sample| *sample == Some(3)));
+        assert_eq!(samples[16], None);
+    }
+
+    #[test]
+    fn exactly_full_store_ends_cleanly() {
+        let mut memory = [0; 8];
+        let mut store = Store::new(&mut memory);
+        store.push(&[0x0002_0001, 0x0004_0003]);
+        store.push(&[0x0006_0005, 0x0008_0007]);
+        assert_eq!(store.head(), (0, 8));
+
+        let samples = unpack(&store, 9);
+        let expected: Vec<_> = (1..=8).rev().map(Some).collect();
+        assert_eq!(samples[..8], expected);
+        assert_eq!(samples[8], None);
+    }
+}
@@ -0,0 +1,685 @@
+use crate::*;
+use pio::{
+    InSource, Instruction, InstructionOperands, JmpCondition, MovDestination, MovOperation,
+    MovSource, OutDestination, SetDestination, WaitSource,
+};
+use std::collections::VecDeque;
+
+/// Number of words each FIFO of a state machine holds.
+const FIFO_DEPTH: usize = 4;
+
+/// Struct representing a host-side model of a single PIO state machine.
+///
+/// Covers the instructions and shift configurations the trigger compiler
+/// emits. Timing is cycle accurate for delays, stalls and clock divisors,
+/// `irq` flags become visible to other state machines within the same cycle.
+/// The FIFOs hold 4 words each like the hardware, 8 for a joined RX FIFO,
+/// blocking `push` and autopush stall while the RX FIFO is full.
+pub struct StateMachine {
+    /// Instruction memory holding the program at offset 0.
+    code: Vec<u16>,
+    /// Side-set configuration of the program.
+    side_set: pio::SideSet,
+    /// Wrap source and target of the program.
+    wrap: pio::Wrap,
+    /// Program counter.
+    pc: u8,
+    /// Scratch register X.
+    x: u32,
+    /// Scratch register Y.
+    y: u32,
+    /// Input shift register.
+    isr: u32,
+    /// Number of bits shifted into ISR.
+    isr_count: u8,
+    /// Output shift register.
+    osr: u32,
+    /// Number of bits shifted out of OSR.
+    osr_count: u8,
+    /// Shift ISR to the right on `in`.
+    in_shift_right: bool,
+    /// Shift OSR to the right on `out`.
+    out_shift_right: bool,
+    /// Push ISR to the RX FIFO once 32 bits are shifted in.
+    autopush: bool,
+    /// GPIO number of the first input pin.
+    in_base: u8,
+    /// GPIO number tested by `jmp pin`.
+    jmp_pin: u8,
+    /// GPIO number of the first side-set pin.
+    side_set_base: u8,
+    /// Clock divisor in 16.8 fixed point.
+    divisor: u32,
+    /// Fractional clock divider accumulator.
+    phase: u32,
+    /// Remaining delay cycles of the last instruction.
+    delay: u8,
+    /// Instruction executed next instead of the one at the program counter.
+    exec: Option<u16>,
+    /// Whether an `irq wait` has raised its flag and waits for it to clear.
+    irq_wait: bool,
+    /// Number of words the RX FIFO holds.
+    rx_depth: usize,
+    /// Words pushed to the RX FIFO.
+    pub rx: VecDeque<u32>,
+    /// Words waiting in the TX FIFO.
+    pub tx: VecDeque<u32>,
+    /// Levels the state machine drives via side-set.
+    pub outputs: u32,
+}
+
+impl StateMachine {
+    /// Creates a state machine with the reset configuration of the HAL builder.
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - Program loaded at offset 0.
+    ///
+    /// # Returns
+    ///
+    /// A new `StateMachine` instance.
+    pub fn new(program: &TriggerProgram) -> Self {
+        Self {
+            code: program.code.to_vec(),
+            side_set: program.side_set,
+            wrap: program.wrap,
+            pc: 0,
+            x: 0,
+            y: 0,
+            isr: 0,
+            isr_count: 0,
+            osr: 0,
+            osr_count: 32,
+            in_shift_right: true,
+            out_shift_right: true,
+            autopush: false,
+            in_base: 0,
+            jmp_pin: 0,
+            side_set_base: 0,
+            divisor: 1 << 8,
+            phase: 0,
+            delay: 0,
+            exec: None,
+            irq_wait: false,
+            rx_depth: FIFO_DEPTH,
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            outputs: 0,
+        }
+    }
+
+    /// Creates a state machine configured like the trigger state machine in `Sampler::start`.
+    ///
+    /// # Arguments
+    ///
+    /// * `trigger` - Trigger configuration to compile.
+    /// * `pretrigger` - Pre-trigger sample count minus one, loaded into the TX FIFO.
+    /// * `posttrigger` - Post-trigger sample count minus one, loaded into the TX FIFO.
+    ///
+    /// # Returns
+    ///
+    /// A new `StateMachine` instance.
+    pub fn trigger(trigger: &Trigger, pretrigger: u32, posttrigger: u32) -> Self {
+        let mut sm = Self::new(&trigger.compile());
+        sm.out_shift_right = false;
+        sm.in_base = PIN_BASE as _;
+        sm.write(pretrigger);
+        sm.write(posttrigger);
+        sm.side_set_base = trigger.output().map_or(0, |out| out.pin());
+        sm.outputs = trigger.output().map_or(0, |out| {
+            (out.idle_state() == hal::pio::PinState::High) as u32
+        }) << sm.side_set_base;
+        sm
+    }
+
+    /// Creates a state machine configured like the capture state machine in `Sampler::start`.
+    ///
+    /// # Arguments
+    ///
+    /// * `width` - Number of bits sampled per clock.
+    /// * `base` - Channel of the lowest sampled bit.
+    ///
+    /// # Returns
+    ///
+    /// A new `StateMachine` instance.
+    pub fn capture(width: u8, base: u8) -> Self {
+        let mut sm = Self::new(&capture_program(width));
+        sm.autopush = true;
+        sm.in_base = PIN_BASE as u8 + base;
+        sm.rx_depth = 2 * FIFO_DEPTH;
+        sm
+    }
+
+    /// Creates a state machine configured like the transition detector in `Sampler::start`.
+    ///
+    /// # Returns
+    ///
+    /// A new `StateMachine` instance.
+    pub fn transitions() -> Self {
+        let mut sm = Self::new(&transition_program());
+        sm.in_shift_right = false;
+        sm.in_base = PIN_BASE as _;
+        sm.rx_depth = 2 * FIFO_DEPTH;
+        sm
+    }
+
+    /// Creates a state machine configured like a helper in `Sampler::start`.
+    ///
+    /// # Arguments
+    ///
+    /// * `helper` - Helper program and its settings.
+    /// * `divisor` - Clock divisor of the capture state machine.
+    ///
+    /// # Returns
+    ///
+    /// A new `StateMachine` instance.
+    pub fn helper(helper: &HelperProgram, divisor: u16) -> Self {
+        let mut sm = Self::new(&helper.program);
+        let (int, frac) = helper.divisor.unwrap_or((divisor, 0));
+        sm.out_shift_right = false;
+        sm.in_shift_right = matches!(helper.in_shift, ShiftDirection::Right);
+        sm.in_base = helper.in_pin_base;
+        sm.jmp_pin = helper.jmp_pin;
+        sm.divisor = (int as u32) << 8 | frac as u32;
+        if let Some(word) = helper.preload {
+            sm.write(word);
+        }
+        sm
+    }
+
+    /// Writes a word into the TX FIFO like `Tx::write`.
+    ///
+    /// # Arguments
+    ///
+    /// * `word` - Word to write.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the FIFO is full and the word was dropped.
+    pub fn write(&mut self, word: u32) -> bool {
+        if self.tx.len() >= FIFO_DEPTH {
+            return false;
+        }
+        self.tx.push_back(word);
+        true
+    }
+
+    /// Empties both FIFOs like `StateMachine::clear_fifos`.
+    pub fn clear_fifos(&mut self) {
+        self.rx.clear();
+        self.tx.clear();
+    }
+
+    /// Returns the wrap target of the program.
+    pub fn wrap_target(&self) -> u8 {
+        self.wrap.target
+    }
+
+    /// Jumps to the wrap target and clears the shift counters like `StateMachine::restart`.
+    pub fn restart(&mut self) {
+        self.pc = self.wrap.target;
+        self.isr = 0;
+        self.isr_count = 0;
+        self.osr_count = 32;
+        self.delay = 0;
+        self.exec = None;
+        self.irq_wait = false;
+    }
+
+    /// Advances the state machine by one system clock cycle.
+    ///
+    /// # Arguments
+    ///
+    /// * `pins` - GPIO input levels.
+    /// * `irq` - PIO IRQ flags shared by the state machines.
+    ///
+    /// # Returns
+    ///
+    /// Address of the instruction completed in this cycle, `None` while
+    /// stalled, delayed, held by the clock divisor or after an instruction
+    /// executed by `out exec` or `mov exec`.
+    pub fn step(&mut self, pins: u32, irq: &mut u8) -> Option<u8> {
+        self.phase += 1 << 8;
+        if self.phase < self.divisor {
+            return None;
+        }
+        self.phase -= self.divisor;
+
+        if self.delay > 0 {
+            self.delay -= 1;
+            return None;
+        }
+
+        // An executed instruction runs in place of the one at the program counter.
+        let (addr, word) = match self.exec {
+            Some(word) => (None, word),
+            None => (Some(self.pc), self.code[self.pc as usize]),
+        };
+        let instr = Instruction::decode(word, self.side_set).expect("invalid instruction");
+        if let Some(side) = instr.side_set {
+            self.outputs &= !(1 << self.side_set_base);
+            self.outputs |= (side as u32 & 1) << self.side_set_base;
+        }
+
+        let mut jump = None;
+        let mut exec = None;
+        match instr.operands {
+            InstructionOperands::JMP { condition, address } => {
+                let taken = match condition {
+                    JmpCondition::Always => true,
+                    JmpCondition::XIsZero => self.x == 0,
+                    JmpCondition::XDecNonZero => {
+                        let taken = self.x != 0;
+                        self.x = self.x.wrapping_sub(1);
+                        taken
+                    }
+                    JmpCondition::YIsZero => self.y == 0,
+                    JmpCondition::YDecNonZero => {
+                        let taken = self.y != 0;
+                        self.y = self.y.wrapping_sub(1);
+                        taken
+                    }
+                    JmpCondition::XNotEqualY => self.x != self.y,
+                    JmpCondition::PinHigh => pins >> self.jmp_pin & 1 == 1,
+                    JmpCondition::OutputShiftRegisterNotEmpty => self.osr_count < 32,
+                };
+                if taken {
+                    jump = Some(address);
+                }
+            }
+            InstructionOperands::WAIT {
+                polarity,
+                source,
+                index,
+                ..
+            } => {
+                let level = match source {
+                    WaitSource::GPIO => pins >> index & 1,
+                    WaitSource::PIN => pins >> ((self.in_base + index) & 31) & 1,
+                    WaitSource::IRQ => (*irq >> (index & 7) & 1) as u32,
+                };
+                if level != polarity as u32 {
+                    return None;
+                }
+                if matches!(source, WaitSource::IRQ) && polarity == 1 {
+                    *irq &= !(1 << (index & 7));
+                }
+            }
+            InstructionOperands::IN { source, bit_count } => {
+                let full = self.rx.len() >= self.rx_depth;
+                if self.autopush && self.isr_count + bits(bit_count) >= 32 && full {
+                    return None;
+                }
+                let data = match source {
+                    InSource::PINS => pins.rotate_right(self.in_base as _),
+                    InSource::X => self.x,
+                    InSource::Y => self.y,
+                    InSource::NULL => 0,
+                    InSource::ISR => self.isr,
+                    InSource::OSR => self.osr,
+                };
+                self.shift_in(data, bit_count);
+            }
+            InstructionOperands::OUT {
+                destination,
+                bit_count,
+            } => {
+                let data = self.shift_out(bit_count);
+                match destination {
+                    OutDestination::PINS | OutDestination::PINDIRS | OutDestination::NULL => {}
+                    OutDestination::X => self.x = data,
+                    OutDestination::Y => self.y = data,
+                    OutDestination::PC => jump = Some(data as u8 & 31),
+                    OutDestination::ISR => {
+                        self.isr = data;
+                        self.isr_count = bits(bit_count);
+                    }
+                    OutDestination::EXEC => exec = Some(data as u16),
+                }
+            }
+            InstructionOperands::PUSH { if_full, block } => {
+                if !if_full || self.isr_count >= 32 {
+                    // A non-blocking push into a full FIFO drops the word.
+                    match self.rx.len() < self.rx_depth {
+                        true => self.rx.push_back(self.isr),
+                        false if block => return None,
+                        false => {}
+                    }
+                    self.isr = 0;
+                    self.isr_count = 0;
+                }
+            }
+            InstructionOperands::PULL { if_empty, block } => {
+                if !if_empty || self.osr_count >= 32 {
+                    match self.tx.pop_front() {
+                        Some(word) => self.osr = word,
+                        None if block => return None,
+                        None => self.osr = self.x,
+                    }
+                    self.osr_count = 0;
+                }
+            }
+            InstructionOperands::MOV {
+                destination,
+                op,
+                source,
+            } => {
+                let data = match source {
+                    MovSource::PINS => pins.rotate_right(self.in_base as _),
+                    MovSource::X => self.x,
+                    MovSource::Y => self.y,
+                    MovSource::NULL => 0,
+                    MovSource::STATUS => 0,
+                    MovSource::ISR => self.isr,
+                    MovSource::OSR => self.osr,
+                };
+                let data = match op {
+                    MovOperation::None => data,
+                    MovOperation::Invert => !data,
+                    MovOperation::BitReverse => data.reverse_bits(),
+                };
+                match destination {
+                    MovDestination::PINS => {}
+                    MovDestination::X => self.x = data,
+                    MovDestination::Y => self.y = data,
+                    MovDestination::EXEC => exec = Some(data as u16),
+                    MovDestination::PC => jump = Some(data as u8 & 31),
+                    MovDestination::ISR => {
+                        self.isr = data;
+                        self.isr_count = 0;
+                    }
+                    MovDestination::OSR => {
+                        self.osr = data;
+                        self.osr_count = 0;
+                    }
+                }
+            }
+            InstructionOperands::IRQ {
+                clear, wait, index, ..
+            } => {
+                let flag = 1 << (index & 7);
+                if clear {
+                    *irq &= !flag;
+                } else if !wait {
+                    *irq |= flag;
+                } else {
+                    // Raise the flag once, then stall until another state machine clears it.
+                    if !self.irq_wait {
+                        *irq |= flag;
+                        self.irq_wait = true;
+                    }
+                    if *irq & flag != 0 {
+                        return None;
+                    }
+                    self.irq_wait = false;
+                }
+            }
+            InstructionOperands::SET { destination, data } => match destination {
+                SetDestination::X => self.x = data as u32,
+                SetDestination::Y => self.y = data as u32,
+                SetDestination::PINS | SetDestination::PINDIRS => {}
+            },
+        }
+
+        self.pc = match (jump, addr) {
+            (Some(address), _) => address,
+            (None, None) => self.pc,
+            (None, Some(addr)) if addr == self.wrap.source => self.wrap.target,
+            (None, Some(addr)) => (addr + 1) & 31,
+        };
+        // Delay cycles of an `exec` are ignored, the executed instruction brings its own.
+        self.delay = match exec {
+            Some(_) => 0,
+            None => instr.delay,
+        };
+        self.exec = exec;
+        addr
+    }
+
+    /// Shifts `bit_count` bits of `data` into ISR, pushing it when autopush is enabled.
+    fn shift_in(&mut self, data: u32, bit_count: u8) {
+        let n = bits(bit_count);
+        let data = data & mask(n);
+        self.isr = match (self.in_shift_right, n) {
+            (_, 32) => data,
+            (true, n) => self.isr >> n | data << (32 - n),
+            (false, n) => self.isr << n | data,
+        };
+        self.isr_count = (self.isr_count + n).min(32);
+        if self.autopush && self.isr_count == 32 {
+            self.rx.push_back(self.isr);
+            self.isr = 0;
+            self.isr_count = 0;
+        }
+    }
+
+    /// Shifts `bit_count` bits out of OSR.
+    fn shift_out(&mut self, bit_count: u8) -> u32 {
+        let n = bits(bit_count);
+        let data = match (self.out_shift_right, n) {
+            (_, 32) => self.osr,
+            (true, n) => self.osr & mask(n),
+            (false, n) => self.osr >> (32 - n),
+        };
+        self.osr = match (self.out_shift_right, n) {
+            (_, 32) => 0,
+            (true, n) => self.osr >> n,
+            (false, n) => self.osr << n,
+        };
+        self.osr_count = (self.osr_count + n).min(32);
+        data
+    }
+}
+
+/// Struct representing a PIO block running the trigger and helper state machines.
+///
+/// The capture state machine only feeds the ring buffer and is left out.
+pub struct Pio {
+    /// State machines, the trigger state machine comes first.
+    pub sms: Vec<StateMachine>,
+    /// Shared IRQ flags.
+    pub irq: u8,
+}
+
+impl Pio {
+    /// Creates a PIO block configured like `Sampler::start` with a divisor of 1
+    /// and no pre-trigger samples.
+    ///
+    /// # Arguments
+    ///
+    /// * `trigger` - Trigger configuration to compile.
+    ///
+    /// # Returns
+    ///
+    /// A new `Pio` instance.
+    pub fn new(trigger: &Trigger) -> Self {
+        Self::with_counts(trigger, 0, 0)
+    }
+
+    /// Creates a PIO block configured like `Sampler::start` with a divisor of 1.
+    ///
+    /// # Arguments
+    ///
+    /// * `trigger` - Trigger configuration to compile.
+    /// * `pretrigger` - Pre-trigger sample count minus one.
+    /// * `posttrigger` - Post-trigger sample count minus one.
+    ///
+    /// # Returns
+    ///
+    /// A new `Pio` instance.
+    pub fn with_counts(trigger: &Trigger, pretrigger: u32, posttrigger: u32) -> Self {
+        let mut sms = vec![StateMachine::trigger(trigger, pretrigger, posttrigger)];
+        sms.extend(
+            trigger
+                .helpers()
+                .iter()
+                .flatten()
+                .map(|helper| StateMachine::helper(helper, 1)),
+        );
+        // Arming holds the helpers back until the pre-trigger samples are in.
+        Self {
+            sms,
+            irq: 1 << PRETRIGGER_IRQ,
+        }
+    }
+
+    /// Re-arms the trigger state machine like `Sampler::start`.
+    ///
+    /// The program starts over from its first instruction with the FIFOs
+    /// cleared by `Sampler::build_trigger`, then the counts are written.
+    ///
+    /// # Arguments
+    ///
+    /// * `pretrigger` - Pre-trigger sample count minus one.
+    /// * `posttrigger` - Post-trigger sample count minus one.
+    pub fn arm(&mut self, pretrigger: u32, posttrigger: u32) {
+        let sm = &mut self.sms[0];
+        sm.restart();
+        sm.pc = 0;
+        sm.clear_fifos();
+        sm.write(pretrigger);
+        sm.write(posttrigger);
+        self.irq = 1 << PRETRIGGER_IRQ;
+    }
+
+    /// Advances all state machines by one system clock cycle.
+    ///
+    /// # Arguments
+    ///
+    /// * `pins` - GPIO input levels.
+    ///
+    /// # Returns
+    ///
+    /// Address of the instruction the trigger state machine completed.
+    pub fn step(&mut self, pins: u32) -> Option<u8> {
+        let mut trigger = None;
+        // Helpers run first so their IRQ reaches the trigger state machine in the same cycle.
+        for (idx, sm) in self.sms.iter_mut().enumerate().rev() {
+            let addr = sm.step(pins, &mut self.irq);
+            if idx == 0 {
+                trigger = addr;
+            }
+        }
+        trigger
+    }
+
+    /// Runs the state machines over `samples`, one sample per clock cycle.
+    ///
+    /// # Arguments
+    ///
+    /// * `samples` - GPIO input levels for each cycle.
+    ///
+    /// # Returns
+    ///
+    /// Index of the sample the trigger fires at, or `None` if the trigger
+    /// never fires.
+    pub fn fire(&mut self, samples: &[u32]) -> Option<usize> {
+        let target = self.sms[0].wrap_target();
+        samples
+            .iter()
+            .position(|pins| self.step(*pins) == Some(target))
+    }
+
+    /// Runs the state machines over `samples` until the ring stops.
+    ///
+    /// # Arguments
+    ///
+    /// * `samples` - GPIO input levels for each cycle.
+    ///
+    /// # Returns
+    ///
+    /// Index of the sample the trigger state machine pushes the stop word
+    /// at, or `None` if it never does.
+    pub fn stop(&mut self, samples: &[u32]) -> Option<usize> {
+        samples.iter().position(|pins| {
+            self.step(*pins);
+            !self.sms[0].rx.is_empty()
+        })
+    }
+}
+
+/// Converts an encoded bit count to the number of bits, 0 meaning 32.
+fn bits(bit_count: u8) -> u8 {
+    match bit_count & 31 {
+        0 => 32,
+        n => n,
+    }
+}
+
+/// Returns a mask of the `n` lowest bits.
+fn mask(n: u8) -> u32 {
+    match n {
+        32 => u32::MAX,
+        n => (1 << n) - 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a state machine running the assembled program.
+    fn machine(asm: TriggerAssembler) -> StateMachine {
+        StateMachine::new(&asm.assemble_program())
+    }
+
+    #[test]
+    fn push_blocks_on_a_full_rx_fifo() {
+        let mut asm = TriggerAssembler::new();
+        asm.set(SetDestination::X, 7);
+        asm.r#in(InSource::X, 32);
+        asm.push(false, true);
+        let mut sm = machine(asm);
+        let mut irq = 0;
+        for _ in 0..64 {
+            sm.step(0, &mut irq);
+        }
+        assert_eq!(sm.rx, [7; FIFO_DEPTH]);
+        // Reading a word lets the stalled push complete.
+        sm.rx.pop_front();
+        sm.step(0, &mut irq);
+        assert_eq!(sm.rx.len(), FIFO_DEPTH);
+        // Writes beyond the depth of the TX FIFO are dropped.
+        assert!((0..FIFO_DEPTH).all(|_| sm.write(0)));
+        assert!(!sm.write(0));
+    }
+
+    #[test]
+    fn out_exec_runs_the_shifted_instruction() {
+        let mut asm = TriggerAssembler::new();
+        asm.pull(false, true);
+        asm.out(OutDestination::EXEC, 16);
+        asm.r#in(InSource::X, 32);
+        asm.push(false, true);
+        let mut sm = machine(asm);
+        let set = Instruction {
+            operands: InstructionOperands::SET {
+                destination: SetDestination::X,
+                data: 5,
+            },
+            delay: 0,
+            side_set: None,
+        };
+        sm.write(set.encode(pio::SideSet::default()) as u32);
+        let mut irq = 0;
+        let addrs: Vec<_> = (0..5).map(|_| sm.step(0, &mut irq)).collect();
+        assert_eq!(addrs, [Some(0), Some(1), None, Some(2), Some(3)]);
+        assert_eq!(sm.rx, [5]);
+    }
+
+    #[test]
+    fn irq_wait_stalls_until_cleared() {
+        let mut asm = TriggerAssembler::new();
+        asm.irq(false, true, 2, false);
+        asm.set(SetDestination::X, 1);
+        let mut sm = machine(asm);
+        let mut irq = 0;
+        assert_eq!(sm.step(0, &mut irq), None);
+        assert_eq!(sm.step(0, &mut irq), None);
+        assert_eq!(irq, 1 << 2);
+        irq = 0;
+        assert_eq!(sm.step(0, &mut irq), Some(0));
+        assert_eq!(sm.step(0, &mut irq), Some(1));
+        assert_eq!(irq, 0);
+    }
+}
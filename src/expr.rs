@@ -0,0 +1,517 @@
+use crate::*;
+
+/// Maximum length of a trigger expression in bytes.
+pub const EXPR_LEN: usize = 128;
+
+/// Enumeration of errors reported by the trigger expression parser.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ParseErrorKind {
+    /// Expression is not valid UTF-8.
+    Encoding = 1,
+    /// Expected a specific token or keyword.
+    Expected = 2,
+    /// Channel number is outside the sampled probes.
+    InvalidChannel = 3,
+    /// Number is malformed or doesn't fit its field.
+    InvalidNumber = 4,
+    /// Stages aren't numbered consecutively from zero.
+    StageOrder = 5,
+    /// Stages need more hardware stages than available.
+    TooManyStages = 6,
+    /// Channel is constrained twice within a stage.
+    Conflict = 7,
+    /// Expression doesn't fit the receive buffer.
+    TooLong = 8,
+    /// Range comparison doesn't fit the instruction budget, or the stages don't fit PIO memory.
+    TooComplex = 9,
+}
+
+/// Struct representing a parse error with the column where parsing failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseError {
+    /// Kind of the error.
+    pub kind: ParseErrorKind,
+    /// One-based column of the offending character.
+    pub column: usize,
+}
+
+/// Struct representing the conditions of a single expression stage.
+#[derive(Default)]
+struct StageExpr {
+    /// Bitmask of the constrained channels.
+    mask: u32,
+    /// Channel levels once all conditions hold.
+    pattern: u32,
+    /// Bitmask of the channels that must transition into their level.
+    edges: u32,
+    /// Sample clocks the conditions have to persist for.
+    hold: u32,
+    /// Bus range condition as first channel, width, bounds and outside flag.
+    range: Option<(u8, u8, u16, u16, bool)>,
+    /// One-based column of the range condition.
+    range_column: usize,
+}
+
+/// Struct representing a recursive descent parser over a trigger expression.
+struct Parser<'a> {
+    /// Expression source.
+    src: &'a [u8],
+    /// Offset of the next unparsed byte.
+    pos: usize,
+}
+
+/// Compiles a trigger expression into trigger stages.
+///
+/// Stages are separated by `;` and numbered consecutively from zero, each
+/// one lists conditions joined by `&` and an optional hold clause:
+///
+/// `stage0: D3=1 & D5=rise; stage1: bus[0:7]==0xA5 for >10`
+///
+/// Channels are compared with `0`, `1`, `rise` or `fall`, channel ranges with
+/// decimal, `0x` hexadecimal or `0b` binary numbers. Binary numbers may use
+/// `x` for don't care bits and `_` as separator, like `0b1x01_xxxx`. Channel
+/// ranges can also be compared against inclusive bounds with `in 0x20..0x7e`
+/// or `!in 0x20..0x7e`, once per stage. An edge occupies an extra hardware
+/// stage waiting for the opposite level first.
+///
+/// # Arguments
+///
+/// * `src` - Trigger expression.
+/// * `base` - Trigger whose outputs and protocol decoder are kept.
+///
+/// # Returns
+///
+/// The compiled `Trigger`, or the error and column where parsing failed.
+pub fn compile(src: &[u8], base: Trigger) -> Result<Trigger, ParseError> {
+    if let Err(err) = core::str::from_utf8(src) {
+        return Err(ParseError {
+            kind: ParseErrorKind::Encoding,
+            column: err.valid_up_to() + 1,
+        });
+    }
+
+    let mut trigger = base;
+    for stage in 0..4 {
+        trigger.set_mask(stage, 0);
+        trigger.set_pattern(stage, 0);
+        trigger.set_count(stage, 0);
+        trigger.set_window(stage, 0);
+        trigger.set_hold(stage, 0);
+        trigger.set_range(stage, None);
+        trigger.set_external(stage, None);
+        trigger.set_group(stage, 0);
+    }
+
+    let mut parser = Parser { src, pos: 0 };
+    let mut hw_stage = 0;
+    let mut index = 0;
+    loop {
+        parser.skip_whitespace();
+        if parser.done() {
+            break;
+        }
+
+        let start = parser.pos;
+        let stage = parser.stage(index)?;
+        let needed = if stage.edges != 0 { 2 } else { 1 };
+        if hw_stage + needed > 4 {
+            return Err(parser.error_at(start, ParseErrorKind::TooManyStages));
+        }
+        if stage.edges != 0 {
+            // Wait for the edge channels to sit at the opposite level first.
+            trigger.set_mask(hw_stage, stage.edges);
+            trigger.set_pattern(hw_stage, !stage.pattern & stage.edges);
+            hw_stage += 1;
+        }
+        trigger.set_mask(hw_stage, stage.mask);
+        trigger.set_pattern(hw_stage, stage.pattern);
+        let held = trigger.set_hold(hw_stage, stage.hold);
+        if !trigger.set_range(hw_stage, stage.range) {
+            return Err(ParseError {
+                kind: ParseErrorKind::TooComplex,
+                column: stage.range_column,
+            });
+        }
+        if !held || !trigger.fits() {
+            // The stages so far don't fit PIO memory next to the capture program.
+            return Err(parser.error_at(start, ParseErrorKind::TooComplex));
+        }
+        hw_stage += 1;
+        index += 1;
+
+        if !parser.eat(";") {
+            parser.skip_whitespace();
+            if !parser.done() {
+                return Err(parser.error(ParseErrorKind::Expected));
+            }
+        }
+    }
+    Ok(trigger)
+}
+
+impl Parser<'_> {
+    /// Parses a stage with the given index.
+    fn stage(&mut self, index: usize) -> Result<StageExpr, ParseError> {
+        self.expect("stage")?;
+        self.skip_whitespace();
+        let start = self.pos;
+        if self.decimal()? as usize != index {
+            return Err(self.error_at(start, ParseErrorKind::StageOrder));
+        }
+        self.expect(":")?;
+
+        let mut stage = StageExpr::default();
+        loop {
+            self.condition(&mut stage)?;
+            if !self.eat("&") {
+                break;
+            }
+        }
+        if self.eat_keyword("for") {
+            self.expect(">")?;
+            stage.hold = self.decimal()?;
+        }
+        Ok(stage)
+    }
+
+    /// Parses a channel or range condition into `stage`.
+    fn condition(&mut self, stage: &mut StageExpr) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let (mask, pattern, edge) = if self.eat_keyword("bus") {
+            self.expect("[")?;
+            let first = self.channel()?;
+            self.expect(":")?;
+            let last = self.channel()?;
+            self.expect("]")?;
+            let (lo, hi) = (first.min(last), first.max(last));
+            let width = hi - lo + 1;
+            if !self.eat("==") {
+                return self.range(stage, start, lo, width);
+            }
+            self.skip_whitespace();
+            let value_start = self.pos;
+            let (care, value) = self.number()?;
+            if value.checked_shr(width).unwrap_or(0) != 0 {
+                return Err(self.error_at(value_start, ParseErrorKind::InvalidNumber));
+            }
+            let care = care & u32::MAX.checked_shr(32 - width).unwrap_or(u32::MAX);
+            (care << lo, value << lo, false)
+        } else {
+            if !self.eat("D") && !self.eat("d") {
+                return Err(self.error(ParseErrorKind::Expected));
+            }
+            let channel = self.channel()?;
+            self.expect("=")?;
+            let bit = 1 << channel;
+            if self.eat_keyword("rise") {
+                (bit, bit, true)
+            } else if self.eat_keyword("fall") {
+                (bit, 0, true)
+            } else if self.eat("1") {
+                (bit, bit, false)
+            } else if self.eat("0") {
+                (bit, 0, false)
+            } else {
+                return Err(self.error(ParseErrorKind::Expected));
+            }
+        };
+
+        if stage.mask & mask != 0 {
+            return Err(self.error_at(start, ParseErrorKind::Conflict));
+        }
+        stage.mask |= mask;
+        stage.pattern |= pattern;
+        if edge {
+            stage.edges |= mask;
+        }
+        Ok(())
+    }
+
+    /// Parses a range comparison of the bus at channel `lo` into `stage`.
+    fn range(
+        &mut self,
+        stage: &mut StageExpr,
+        start: usize,
+        lo: u32,
+        width: u32,
+    ) -> Result<(), ParseError> {
+        if width == 0 || (lo + width) as usize > PROBES {
+            return Err(self.error_at(start, ParseErrorKind::InvalidChannel));
+        }
+        let outside = self.eat("!");
+        if !self.eat_keyword("in") {
+            return Err(self.error(ParseErrorKind::Expected));
+        }
+        let min = self.bound(width)?;
+        self.expect("..")?;
+        let max = self.bound(width)?;
+        if stage.range.is_some() {
+            return Err(self.error_at(start, ParseErrorKind::Conflict));
+        }
+        stage.range = Some((lo as u8, width as u8, min, max, outside));
+        stage.range_column = start + 1;
+        Ok(())
+    }
+
+    /// Parses a range bound fitting a bus of `width` bits.
+    fn bound(&mut self, width: u32) -> Result<u16, ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let (care, value) = self.number()?;
+        let bits = u32::MAX.checked_shr(32 - width).unwrap_or(u32::MAX);
+        if care & bits != bits || value & !bits != 0 {
+            return Err(self.error_at(start, ParseErrorKind::InvalidNumber));
+        }
+        Ok(value as u16)
+    }
+
+    /// Parses a channel number within the sampled probes.
+    fn channel(&mut self) -> Result<u32, ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        match self.decimal()? {
+            channel if (channel as usize) < PROBES => Ok(channel),
+            _ => Err(self.error_at(start, ParseErrorKind::InvalidChannel)),
+        }
+    }
+
+    /// Parses a decimal, hexadecimal or binary number.
+    ///
+    /// Returns the mask of the specified bits and the value.
+    fn number(&mut self) -> Result<(u32, u32), ParseError> {
+        self.skip_whitespace();
+        let (radix, digits) = if self.eat("0x") || self.eat("0X") {
+            (16, "0123456789abcdef_")
+        } else if self.eat("0b") || self.eat("0B") {
+            (2, "01x_")
+        } else {
+            return self.decimal().map(|value| (u32::MAX, value));
+        };
+
+        let start = self.pos;
+        let mut care = 0u32;
+        let mut value = 0u32;
+        let mut bits = 0;
+        while let Some(&ch) = self.src.get(self.pos) {
+            let ch = ch.to_ascii_lowercase();
+            let Some(digit) = digits.bytes().position(|d| d == ch) else {
+                break;
+            };
+            self.pos += 1;
+            if ch == b'_' {
+                continue;
+            }
+            let (digit_care, digit_value) = match (radix, ch) {
+                (2, b'x') => (0, 0),
+                (2, _) => (1, digit as u32),
+                _ => (0xf, digit as u32),
+            };
+            let shift = if radix == 2 { 1 } else { 4 };
+            bits += shift;
+            if bits > 32 {
+                return Err(self.error_at(start, ParseErrorKind::InvalidNumber));
+            }
+            care = care.checked_shl(shift).unwrap_or(0) | digit_care;
+            value = value.checked_shl(shift).unwrap_or(0) | digit_value;
+        }
+        if bits == 0 {
+            return Err(self.error(ParseErrorKind::InvalidNumber));
+        }
+        // Leading digits left out are zeros the user cares about.
+        let care = care | !(u32::MAX.checked_shr(32 - bits).unwrap_or(0));
+        Ok((care, value))
+    }
+
+    /// Parses a decimal number.
+    fn decimal(&mut self) -> Result<u32, ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let mut value = 0u32;
+        while let Some(digit) = self.src.get(self.pos).filter(|ch| ch.is_ascii_digit()) {
+            value = value
+                .checked_mul(10)
+                .and_then(|value| value.checked_add((digit - b'0') as u32))
+                .ok_or(self.error_at(start, ParseErrorKind::InvalidNumber))?;
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return Err(self.error(ParseErrorKind::InvalidNumber));
+        }
+        Ok(value)
+    }
+
+    /// Consumes `token` if it comes next, skipping whitespace.
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.src[self.pos..].starts_with(token.as_bytes()) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consumes `keyword` if it comes next and isn't followed by more letters.
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        self.skip_whitespace();
+        let end = self.pos + keyword.len();
+        let follows = self.src.get(end).is_some_and(|ch| ch.is_ascii_alphabetic());
+        !follows && self.eat(keyword)
+    }
+
+    /// Consumes `token` or fails with the column of what comes instead.
+    fn expect(&mut self, token: &str) -> Result<(), ParseError> {
+        match self.eat(token) {
+            true => Ok(()),
+            false => Err(self.error(ParseErrorKind::Expected)),
+        }
+    }
+
+    /// Skips spaces, tabs and line breaks.
+    fn skip_whitespace(&mut self) {
+        while self
+            .src
+            .get(self.pos)
+            .is_some_and(|ch| ch.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    /// Checks whether the whole expression has been consumed.
+    fn done(&self) -> bool {
+        self.pos >= self.src.len()
+    }
+
+    /// Returns an error at the current position.
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        self.error_at(self.pos, kind)
+    }
+
+    /// Returns an error at the given byte offset.
+    fn error_at(&self, pos: usize, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            kind,
+            column: pos + 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compiles `src` into a fresh trigger.
+    fn parse(src: &str) -> Result<Trigger, ParseError> {
+        compile(src.as_bytes(), Trigger::default())
+    }
+
+    /// Returns the error of compiling `src` as kind and column.
+    fn error(src: &str) -> (ParseErrorKind, usize) {
+        let err = parse(src).err().expect("expression should not compile");
+        (err.kind, err.column)
+    }
+
+    #[test]
+    fn levels_and_ranges() {
+        let trigger = parse("stage0: D3=1 & D4=0; stage1: bus[0:7]==0xA5").unwrap();
+        let mut expected = Trigger::default();
+        expected.set_mask(0, 0b1_1000);
+        expected.set_pattern(0, 0b0_1000);
+        expected.set_mask(1, 0xff);
+        expected.set_pattern(1, 0xa5);
+        assert_eq!(trigger.stages(), expected.stages());
+    }
+
+    #[test]
+    fn binary_dont_care_bits() {
+        let trigger = parse("stage0: bus[15:8] == 0b1x01_xxxx").unwrap();
+        let mut expected = Trigger::default();
+        expected.set_mask(0, 0b1011_0000 << 8);
+        expected.set_pattern(0, 0b1001_0000 << 8);
+        assert_eq!(trigger.stages(), expected.stages());
+    }
+
+    #[test]
+    fn edges_take_an_extra_stage() {
+        let trigger = parse("stage0: D3=1 & D5=rise; stage1: D0=fall").unwrap();
+        let mut expected = Trigger::default();
+        expected.set_mask(0, 1 << 5);
+        expected.set_pattern(0, 0);
+        expected.set_mask(1, 1 << 3 | 1 << 5);
+        expected.set_pattern(1, 1 << 3 | 1 << 5);
+        expected.set_mask(2, 1);
+        expected.set_pattern(2, 1);
+        expected.set_mask(3, 1);
+        expected.set_pattern(3, 0);
+        assert_eq!(trigger.stages(), expected.stages());
+    }
+
+    #[test]
+    fn hold_clause() {
+        let trigger = parse("stage0: bus[0:7]==0xA5 for >10").unwrap();
+        let mut expected = Trigger::default();
+        expected.set_mask(0, 0xff);
+        expected.set_pattern(0, 0xa5);
+        expected.set_hold(0, 10);
+        assert_eq!(trigger.stages(), expected.stages());
+    }
+
+    #[test]
+    fn range_conditions() {
+        let trigger = parse("stage0: D15=1 & bus[0:7] in 0x20..0x7e").unwrap();
+        let mut expected = Trigger::default();
+        expected.set_mask(0, 1 << 15);
+        expected.set_pattern(0, 1 << 15);
+        expected.set_range(0, Some((0, 8, 0x20, 0x7e, false)));
+        assert_eq!(trigger.stages(), expected.stages());
+        let trigger = parse("stage0: D1=0; stage1: bus[8:11] !in 2..5").unwrap();
+        let mut expected = Trigger::default();
+        expected.set_mask(0, 1 << 1);
+        expected.set_range(1, Some((8, 4, 2, 5, true)));
+        assert_eq!(trigger.stages(), expected.stages());
+
+        // Both ranges fit the budget on their own, but not next to each other.
+        assert_eq!(
+            error("stage0: D15=1 & bus[0:7] in 0x20..0x7e; stage1: bus[8:11] !in 2..5"),
+            (ParseErrorKind::TooComplex, 41)
+        );
+
+        assert_eq!(
+            error("stage0: bus[0:7] in 0x55..0xaa"),
+            (ParseErrorKind::TooComplex, 9)
+        );
+        assert_eq!(
+            error("stage0: D1=1; stage1: bus[0:15]==0x5555 for >1000"),
+            (ParseErrorKind::TooComplex, 15)
+        );
+        assert_eq!(
+            error("stage0: bus[0:3] in 0b1x..3"),
+            (ParseErrorKind::InvalidNumber, 21)
+        );
+        assert_eq!(
+            error("stage0: bus[0:3] in 1..2 & bus[4:7] in 1..2"),
+            (ParseErrorKind::Conflict, 28)
+        );
+    }
+
+    #[test]
+    fn errors_report_column() {
+        assert_eq!(error("stage0 D3=1"), (ParseErrorKind::Expected, 8));
+        assert_eq!(error("stage0: D16=1"), (ParseErrorKind::InvalidChannel, 10));
+        assert_eq!(error("stage0: D3=2"), (ParseErrorKind::Expected, 12));
+        assert_eq!(error("stage1: D3=1"), (ParseErrorKind::StageOrder, 6));
+        assert_eq!(error("stage0: D3=1 & D3=0"), (ParseErrorKind::Conflict, 16));
+        assert_eq!(
+            error("stage0: bus[0:3]==0x1f"),
+            (ParseErrorKind::InvalidNumber, 19)
+        );
+        assert_eq!(error("stage0: D3=1 stage1"), (ParseErrorKind::Expected, 14));
+        assert_eq!(
+            error("stage0: D0=rise; stage1: D1=rise; stage2: D2=1"),
+            (ParseErrorKind::TooManyStages, 35)
+        );
+    }
+}
@@ -1,39 +1,56 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// Host tests only exercise the trigger compiler, the firmware app is compiled out.
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
+#[cfg(not(test))]
 extern crate panic_probe;
 extern crate rp2040_hal as hal;
 extern crate rtic;
 
+mod analog;
 mod analyzer;
+mod clock;
+mod compress;
+#[cfg(test)]
+mod emulator;
+mod expr;
+mod protocol;
 mod sampler;
+mod stream;
 mod trigger;
 
+#[cfg(not(test))]
 use defmt_rtt as _;
 
+use analog::*;
 use analyzer::*;
+use clock::*;
+use compress::*;
 use cortex_m::singleton;
 use embedded_hal::digital::OutputPin;
+use expr::{ParseError, ParseErrorKind, EXPR_LEN};
 use hal::dma::{self, *};
 use hal::gpio::*;
 use hal::pac;
 use hal::pio::*;
 use hal::usb::UsbBus;
-use rtic_monotonics::rp2040_timer_monotonic;
+use protocol::*;
+use rtic_common::waker_registration::CriticalSectionWakerRegistration;
+use rtic_monotonics::rp2040::prelude::*;
 use sampler::*;
+use stream::*;
 use trigger::*;
 use usb_device::{class_prelude::*, prelude::*};
 use usbd_serial::SerialPort;
 
 /// Number of probes available for the analyzer.
 pub const PROBES: usize = 16;
-/// Memory size allocated for sample storage.
-pub const SAMPLE_MEMORY: usize = 200_000;
-/// Sampling rate in Hertz.
-pub const SAMPLE_RATE: usize = 100_000_000;
 
 /// Base pin number for PIO operations.
 pub const PIN_BASE: usize = 0;
+/// Auxiliary pins available for trigger inputs and outputs.
+pub const AUX_PINS: core::ops::RangeInclusive<u8> = 16..=22;
 /// Frequency of the external crystal oscillator in Hertz.
 pub const XTAL_FREQ_HZ: u32 = 12_000_000_u32;
 
@@ -53,7 +70,15 @@ pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_GENERIC_03H;
 
 rp2040_timer_monotonic!(Mono);
 
-#[rtic::app(device = pac, peripherals = true)]
+/// Type alias for the monotonic timer instant.
+pub type Instant = <Mono as Monotonic>::Instant;
+
+/// Wakes the auto-trigger task early when the USB handler changes its deadline.
+static AUTO_TRIGGER_WAKER: CriticalSectionWakerRegistration =
+    CriticalSectionWakerRegistration::new();
+
+#[cfg(not(test))]
+#[rtic::app(device = pac, peripherals = true, dispatchers = [SW0_IRQ])]
 mod app {
     use super::*;
 
@@ -91,12 +116,11 @@ mod app {
         .ok()
         .unwrap();
 
-        // Manually set PLL dividers.
-        unsafe {
-            let pll = &*pac::PLL_SYS::ptr();
-            pll.prim()
-                .modify(|_, w| w.postdiv1().bits(5).postdiv2().bits(3));
-        };
+        // Reprogram PLL_SYS for the system clock selected at build time.
+        let sys_clock = SysClock::new(ctx.device.VREG_AND_CHIP_RESET);
+
+        // Start the monotonic timer.
+        Mono::start(ctx.device.TIMER, &resets);
 
         // Initialize USB peripheral.
         let usb_regs = ctx.device.USBCTRL_REGS;
@@ -125,9 +149,11 @@ mod app {
         // Split DMA channels.
         let dma = ctx.device.DMA.split(&mut resets);
         // Split PIO0 into individual components.
-        let (pio, sm, _, _, _) = ctx.device.PIO0.split(&mut resets);
+        let (pio, sm0, sm1, sm2, sm3) = ctx.device.PIO0.split(&mut resets);
+        // PIO1 streams and detects transitions, reading the pins doesn't need them assigned.
+        let (pio1, sm1_0, sm1_1, _, _) = ctx.device.PIO1.split(&mut resets);
 
-        // Initialize SIO and configure GPIO pins.
+        // Initialize SIO and configure GPIO pins, the FIFO is kept to talk to core1.
         let sio = hal::Sio::new(ctx.device.SIO);
         let pins = Pins::new(
             ctx.device.IO_BANK0,
@@ -154,10 +180,38 @@ mod app {
         pins.gpio14.into_function::<FunctionPio0>();
         pins.gpio15.into_function::<FunctionPio0>();
 
+        // Hand auxiliary pins to PIO, they stay inputs until a trigger output is configured.
+        pins.gpio16.into_function::<FunctionPio0>();
+        pins.gpio17.into_function::<FunctionPio0>();
+        pins.gpio18.into_function::<FunctionPio0>();
+        pins.gpio19.into_function::<FunctionPio0>();
+        pins.gpio20.into_function::<FunctionPio0>();
+        pins.gpio21.into_function::<FunctionPio0>();
+        pins.gpio22.into_function::<FunctionPio0>();
+
+        // Hand GPIO 26-29 to the ADC for the analog capture.
+        let adc = hal::adc::Adc::new(ctx.device.ADC, &mut resets);
+        let analog_pins = (
+            hal::adc::AdcPin::new(pins.gpio26.into_floating_input()).unwrap(),
+            hal::adc::AdcPin::new(pins.gpio27.into_floating_input()).unwrap(),
+            hal::adc::AdcPin::new(pins.gpio28.into_floating_input()).unwrap(),
+            hal::adc::AdcPin::new(pins.gpio29.into_floating_input()).unwrap(),
+        );
+
         // Initialize the status LED as a push-pull output.
         let status_led = pins.gpio25.into_push_pull_output();
+        // Create the sampler, it starts the compression loop on core1.
+        let core1 = (ctx.device.PSM, ctx.device.PPB, sio.fifo);
+        let sampler = Sampler::new(
+            pio,
+            (sm0, sm1, sm2, sm3),
+            (pio1, sm1_0, sm1_1),
+            dma,
+            core1,
+            (adc, analog_pins),
+        );
         // Create a new instance of the Logic Analyzer.
-        let analyzer = LogicAnalyzer::new(usb_dev, serial, pio, sm, dma, status_led);
+        let analyzer = LogicAnalyzer::new(usb_dev, serial, sampler, sys_clock, status_led);
 
         (Shared { analyzer }, Local {})
     }
@@ -168,13 +222,54 @@ mod app {
     /// for incoming serial commands.
     #[task(binds = USBCTRL_IRQ, shared = [analyzer])]
     fn usb_irq(mut ctx: usb_irq::Context) {
-        ctx.shared.analyzer.lock(|analyzer| analyzer.poll_serial());
+        let deadline = ctx.shared.analyzer.lock(|analyzer| {
+            analyzer.poll_serial();
+            analyzer.auto_trigger_deadline()
+        });
+        if deadline.is_some() && auto_trigger::spawn().is_err() {
+            // Still waiting on the last capture, wake it to pick up the new deadline.
+            AUTO_TRIGGER_WAKER.wake();
+        }
+    }
+
+    /// Software task forcing the capture to start once the auto-trigger timeout expires.
+    ///
+    /// The deadline is re-read after every wake-up, so re-arming the analyzer
+    /// or a regular trigger match cancels the pending timeout, and a shorter
+    /// one takes effect right away.
+    #[task(shared = [analyzer])]
+    async fn auto_trigger(mut ctx: auto_trigger::Context) {
+        while let Some(deadline) = ctx
+            .shared
+            .analyzer
+            .lock(|analyzer| analyzer.auto_trigger_deadline())
+        {
+            if Mono::now() >= deadline {
+                ctx.shared
+                    .analyzer
+                    .lock(|analyzer| analyzer.force_trigger());
+                break;
+            }
+            // Sleep until the deadline or until it changes, whichever comes first.
+            let rearmed = core::future::poll_fn(|cx| {
+                AUTO_TRIGGER_WAKER.register(cx.waker());
+                match ctx
+                    .shared
+                    .analyzer
+                    .lock(|analyzer| analyzer.auto_trigger_deadline())
+                {
+                    current if current == Some(deadline) => core::task::Poll::Pending,
+                    _ => core::task::Poll::Ready(()),
+                }
+            });
+            Mono::timeout_at(deadline, rearmed).await.ok();
+        }
     }
 
     /// Interrupt handler for DMA channel 0 events.
     ///
     /// This task is triggered by DMA interrupts and notifies the Logic Analyzer
-    /// that data acquisition has been completed.
+    /// that data acquisition has been completed or a streaming half is full.
     #[task(binds = DMA_IRQ_0, shared = [analyzer])]
     fn dma_irq(mut ctx: dma_irq::Context) {
         ctx.shared
@@ -0,0 +1,492 @@
+use crate::*;
+
+/// Enumeration of protocol decoders that can release the capture.
+#[derive(Clone, Copy)]
+pub enum ProtocolTrigger {
+    /// UART receiver matching a short byte sequence.
+    Uart(UartTrigger),
+    /// I2C receiver matching an address and an optional data byte.
+    I2c(I2cTrigger),
+    /// SPI receiver matching a masked word while chip select is asserted.
+    Spi(SpiTrigger),
+}
+
+impl ProtocolTrigger {
+    /// Creates a protocol trigger from the raw configuration bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - Protocol selector: 1 for UART, 2 for I2C, 3 for SPI.
+    /// * `params` - Protocol specific parameters.
+    ///
+    /// # Returns
+    ///
+    /// A new `ProtocolTrigger`, or `None` if the configuration is invalid.
+    pub fn new(kind: u8, params: [u8; 3]) -> Option<Self> {
+        match kind {
+            1 => UartTrigger::new(params[0], params[1], params[2]).map(ProtocolTrigger::Uart),
+            2 => I2cTrigger::new(params[0], params[1], params[2]).map(ProtocolTrigger::I2c),
+            3 => SpiTrigger::new(
+                params[0] & 0x0f,
+                params[0] >> 4,
+                params[1] & 0x0f,
+                params[1] >> 4,
+                params[2],
+            )
+            .map(ProtocolTrigger::Spi),
+            _ => None,
+        }
+    }
+
+    /// Sets the value the decoder matches against.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Protocol specific value to match.
+    pub fn set_value(&mut self, value: u32) {
+        match self {
+            ProtocolTrigger::Uart(uart) => uart.value = value,
+            ProtocolTrigger::I2c(i2c) => i2c.value = value,
+            ProtocolTrigger::Spi(spi) => spi.pattern = value,
+        }
+    }
+
+    /// Sets the mask of the bits the decoder compares.
+    ///
+    /// # Arguments
+    ///
+    /// * `mask` - Bitmask of the compared bits, only SPI words support masking.
+    pub fn set_mask(&mut self, mask: u32) {
+        if let ProtocolTrigger::Spi(spi) = self {
+            spi.mask = mask;
+        }
+    }
+
+    /// Sets the bit rate of the decoded protocol.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - Bit rate in bits per second.
+    pub fn set_rate(&mut self, rate: u32) {
+        match self {
+            ProtocolTrigger::Uart(uart) => uart.baud = rate.max(1),
+            // Synchronous protocols are clocked by the bus, the rate doesn't matter.
+            ProtocolTrigger::I2c(_) | ProtocolTrigger::Spi(_) => {}
+        }
+    }
+
+    /// Returns the number of instructions the decoder program takes.
+    ///
+    /// SPI words are matched bit by bit, their program may not fit PIO memory.
+    pub fn len(&self) -> usize {
+        match self {
+            ProtocolTrigger::Spi(spi) => spi.len(),
+            ProtocolTrigger::Uart(_) | ProtocolTrigger::I2c(_) => self.compile().program.code.len(),
+        }
+    }
+
+    /// Compiles the decoder into a helper PIO program.
+    ///
+    /// # Returns
+    ///
+    /// A `HelperProgram` raising `TRIGGER_IRQ` when the decoded value matches.
+    pub fn compile(&self) -> HelperProgram {
+        match self {
+            ProtocolTrigger::Uart(uart) => uart.compile(),
+            ProtocolTrigger::I2c(i2c) => i2c.compile(),
+            ProtocolTrigger::Spi(spi) => spi.compile(),
+        }
+    }
+}
+
+/// Struct representing a UART receiver matching a short byte sequence.
+#[derive(Clone, Copy)]
+pub struct UartTrigger {
+    /// Input channel carrying the UART line.
+    channel: u8,
+    /// Number of data bits per frame (5-8).
+    data_bits: u8,
+    /// Number of frames in the sequence (1-4).
+    length: u8,
+    /// Baud rate in bits per second.
+    baud: u32,
+    /// Frames to match, the first received frame in the lowest byte.
+    value: u32,
+}
+
+impl UartTrigger {
+    /// Number of PIO cycles per UART bit.
+    const OVERSAMPLING: u32 = 8;
+
+    /// Creates a new UART trigger at 115200 baud matching zero bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - Input channel carrying the UART line.
+    /// * `data_bits` - Number of data bits per frame (5-8).
+    /// * `length` - Number of frames in the sequence (1-4).
+    ///
+    /// # Returns
+    ///
+    /// A new `UartTrigger`, or `None` if any argument is out of range.
+    pub fn new(channel: u8, data_bits: u8, length: u8) -> Option<Self> {
+        if channel as usize >= PROBES || !(5..=8).contains(&data_bits) || !(1..=4).contains(&length)
+        {
+            return None;
+        }
+        Some(Self {
+            channel,
+            data_bits,
+            length,
+            baud: 115_200,
+            value: 0,
+        })
+    }
+
+    /// Compiles the UART receiver into a helper PIO program.
+    ///
+    /// Received bits are shifted right into ISR, so ISR always holds the most
+    /// recent frames with the newest one on top. After every frame the top
+    /// `data_bits * length` bits are compared against the expected sequence.
+    /// Parity and stop bits are skipped by waiting for the idle line level.
+    ///
+    /// # Returns
+    ///
+    /// A `HelperProgram` raising `TRIGGER_IRQ` on the last bit of a matching sequence.
+    pub fn compile(&self) -> HelperProgram {
+        let mut asm = TriggerAssembler::new();
+        let mut wrap_target = asm.label();
+        let mut wrap_source = asm.label();
+        let mut bit_loop = asm.label();
+        let mut idle = asm.label();
+
+        // Frames within the pre-trigger samples don't count.
+        asm.wait(0, pio::WaitSource::IRQ, PRETRIGGER_IRQ, false);
+        // Expected sequence arrives through the TX FIFO and stays in Y.
+        asm.pull(false, true);
+        asm.mov(
+            pio::MovDestination::Y,
+            pio::MovOperation::None,
+            pio::MovSource::OSR,
+        );
+
+        // Wait for the start bit and skip to the middle of the first data bit.
+        asm.bind(&mut wrap_target);
+        asm.wait(0, pio::WaitSource::PIN, 0, false);
+        asm.set_with_delay(pio::SetDestination::X, self.data_bits - 1, 10);
+        asm.bind(&mut bit_loop);
+        asm.r#in(pio::InSource::PINS, 1);
+        asm.jmp_with_delay(pio::JmpCondition::XDecNonZero, &mut bit_loop, 6);
+
+        // Compare the most recent frames against the expected sequence.
+        asm.mov(
+            pio::MovDestination::OSR,
+            pio::MovOperation::None,
+            pio::MovSource::ISR,
+        );
+        asm.out(pio::OutDestination::X, self.data_bits * self.length);
+        asm.jmp(pio::JmpCondition::XNotEqualY, &mut idle);
+        asm.irq(false, false, TRIGGER_IRQ, false);
+
+        // Let parity and stop bits pass before looking for the next start bit.
+        asm.bind(&mut idle);
+        asm.wait(1, pio::WaitSource::PIN, 0, false);
+        asm.bind(&mut wrap_source);
+
+        // Pack the expected frames the same way they land in ISR.
+        let frame_mask = (1 << self.data_bits) - 1;
+        let expected = (0..self.length as u32).fold(0, |acc, frame| {
+            acc | (self.value >> (frame * 8) & frame_mask) << (frame * self.data_bits as u32)
+        });
+
+        // Clock divisor in 8.8 fixed point for the oversampled bit rate.
+        let divisor = (sample_rate() as u64 * 256 / (self.baud as u64 * Self::OVERSAMPLING as u64))
+            .clamp(0x100, 0xff_ffff) as u32;
+
+        HelperProgram {
+            program: asm.assemble_with_wrap(wrap_source, wrap_target),
+            divisor: Some(((divisor >> 8) as u16, divisor as u8)),
+            in_pin_base: (PIN_BASE + self.channel as usize) as _,
+            in_shift: ShiftDirection::Right,
+            jmp_pin: 0,
+            preload: Some(expected),
+        }
+    }
+}
+
+/// Struct representing an I2C receiver matching an address and an optional data byte.
+#[derive(Clone, Copy)]
+pub struct I2cTrigger {
+    /// Input channel carrying SDA.
+    sda: u8,
+    /// Input channel carrying SCL.
+    scl: u8,
+    /// Match a 10-bit address instead of a 7-bit one.
+    ten_bit: bool,
+    /// Match the first data byte after the address.
+    with_data: bool,
+    /// Address in bits 0-9, R/W bit in bit 10 and data byte in bits 16-23.
+    value: u32,
+}
+
+impl I2cTrigger {
+    /// Creates a new I2C trigger matching a write to address zero.
+    ///
+    /// # Arguments
+    ///
+    /// * `sda` - Input channel carrying SDA.
+    /// * `scl` - Input channel carrying SCL.
+    /// * `flags` - Bit 0 selects 10-bit addressing, bit 1 matches a data byte.
+    ///
+    /// # Returns
+    ///
+    /// A new `I2cTrigger`, or `None` if any channel is out of range.
+    pub fn new(sda: u8, scl: u8, flags: u8) -> Option<Self> {
+        if sda as usize >= PROBES || scl as usize >= PROBES || sda == scl {
+            return None;
+        }
+        Some(Self {
+            sda,
+            scl,
+            ten_bit: flags & 1 != 0,
+            with_data: flags & 2 != 0,
+            value: 0,
+        })
+    }
+
+    /// Returns the bytes following the START condition, packed MSB first.
+    ///
+    /// 10-bit addresses are matched against the write addressing form:
+    /// the `11110xx` header followed by the low address byte. Reads start
+    /// with it too before the repeated START, so the R/W bit is ignored.
+    ///
+    /// # Returns
+    ///
+    /// Packed bytes and their count.
+    fn expected(&self) -> (u32, u8) {
+        let address = self.value & 0x3ff;
+        let rw = match self.ten_bit {
+            true => 0,
+            false => self.value >> 10 & 1,
+        };
+        let mut bytes = if self.ten_bit {
+            (0xf0 | (address >> 7 & 0x06) | rw) << 8 | (address & 0xff)
+        } else {
+            (address << 1 & 0xfe) | rw
+        };
+        let mut count = 1 + self.ten_bit as u8;
+        if self.with_data {
+            bytes = bytes << 8 | (self.value >> 16 & 0xff);
+            count += 1;
+        }
+        (bytes, count)
+    }
+
+    /// Compiles the I2C receiver into a helper PIO program.
+    ///
+    /// A falling SDA while SCL is high marks a START, after which the bytes are
+    /// shifted into ISR on every rising SCL edge, skipping the ACK bits. The
+    /// expected bytes stay in OSR and are copied to Y for the comparison.
+    ///
+    /// # Returns
+    ///
+    /// A `HelperProgram` raising `TRIGGER_IRQ` after the last matching byte.
+    pub fn compile(&self) -> HelperProgram {
+        let (expected, count) = self.expected();
+        let scl = (PIN_BASE + self.scl as usize) as u8;
+
+        let mut asm = TriggerAssembler::new();
+        let mut wrap_target = asm.label();
+        let mut wrap_source = asm.label();
+        let mut start = asm.label();
+        let mut byte_loop = asm.label();
+        let mut bit_loop = asm.label();
+
+        // Transfers within the pre-trigger samples don't count.
+        asm.wait(0, pio::WaitSource::IRQ, PRETRIGGER_IRQ, false);
+        // Expected bytes arrive through the TX FIFO and stay in OSR.
+        asm.pull(false, true);
+
+        // Wait for SDA to fall while SCL is high.
+        asm.bind(&mut wrap_target);
+        asm.wait(1, pio::WaitSource::PIN, 0, false);
+        asm.wait(0, pio::WaitSource::PIN, 0, false);
+        asm.jmp(pio::JmpCondition::PinHigh, &mut start);
+        asm.jmp(pio::JmpCondition::Always, &mut wrap_target);
+
+        // Shift in the address and data bytes, Y counts bytes and X counts bits.
+        asm.bind(&mut start);
+        asm.mov(
+            pio::MovDestination::ISR,
+            pio::MovOperation::None,
+            pio::MovSource::NULL,
+        );
+        asm.set(pio::SetDestination::Y, count - 1);
+        asm.bind(&mut byte_loop);
+        asm.set(pio::SetDestination::X, 7);
+        asm.bind(&mut bit_loop);
+        asm.wait(0, pio::WaitSource::GPIO, scl, false);
+        asm.wait(1, pio::WaitSource::GPIO, scl, false);
+        asm.r#in(pio::InSource::PINS, 1);
+        asm.jmp(pio::JmpCondition::XDecNonZero, &mut bit_loop);
+        asm.wait(0, pio::WaitSource::GPIO, scl, false);
+        asm.wait(1, pio::WaitSource::GPIO, scl, false);
+        asm.jmp(pio::JmpCondition::YDecNonZero, &mut byte_loop);
+
+        // Compare the received bytes and go back to waiting for a START.
+        asm.mov(
+            pio::MovDestination::X,
+            pio::MovOperation::None,
+            pio::MovSource::ISR,
+        );
+        asm.mov(
+            pio::MovDestination::Y,
+            pio::MovOperation::None,
+            pio::MovSource::OSR,
+        );
+        asm.jmp(pio::JmpCondition::XNotEqualY, &mut wrap_target);
+        asm.irq(false, false, TRIGGER_IRQ, false);
+        asm.bind(&mut wrap_source);
+
+        HelperProgram {
+            program: asm.assemble_with_wrap(wrap_source, wrap_target),
+            divisor: Some((1, 0)),
+            in_pin_base: (PIN_BASE + self.sda as usize) as _,
+            in_shift: ShiftDirection::Left,
+            jmp_pin: scl,
+            preload: Some(expected),
+        }
+    }
+}
+
+/// Struct representing an SPI receiver matching a masked word while chip select is asserted.
+#[derive(Clone, Copy)]
+pub struct SpiTrigger {
+    /// Input channel carrying the active low chip select.
+    cs: u8,
+    /// Input channel carrying the clock.
+    sck: u8,
+    /// Input channel carrying MOSI or MISO.
+    data: u8,
+    /// SPI mode, bit 1 is CPOL and bit 0 is CPHA.
+    mode: u8,
+    /// Number of bits per word (1-32).
+    bits: u8,
+    /// Bitmask of the compared word bits.
+    mask: u32,
+    /// Expected values of the masked word bits.
+    pattern: u32,
+}
+
+impl SpiTrigger {
+    /// Creates a new SPI trigger matching any word.
+    ///
+    /// # Arguments
+    ///
+    /// * `cs` - Input channel carrying the active low chip select.
+    /// * `sck` - Input channel carrying the clock.
+    /// * `data` - Input channel carrying MOSI or MISO.
+    /// * `mode` - SPI mode (0-3).
+    /// * `bits` - Number of bits per word (1-32).
+    ///
+    /// # Returns
+    ///
+    /// A new `SpiTrigger`, or `None` if any argument is out of range.
+    pub fn new(cs: u8, sck: u8, data: u8, mode: u8, bits: u8) -> Option<Self> {
+        if [cs, sck, data].iter().any(|ch| *ch as usize >= PROBES)
+            || mode > 3
+            || !(1..=32).contains(&bits)
+        {
+            return None;
+        }
+        Some(Self {
+            cs,
+            sck,
+            data,
+            mode,
+            bits,
+            mask: 0,
+            pattern: 0,
+        })
+    }
+
+    /// Compiles the SPI receiver into a helper PIO program.
+    ///
+    /// Words are shifted into ISR MSB first on the sampling edge and aligned to
+    /// the first clock after chip select asserts. Every word is bit-reversed into
+    /// OSR and matched with the same code as the trigger stages, so `mask` and
+    /// `pattern` use the natural bit order of the word. Chip select is checked
+    /// between words, a transfer aborted mid-word realigns on the next assertion
+    /// only after the partial word completes.
+    ///
+    /// # Returns
+    ///
+    /// A `HelperProgram` raising `TRIGGER_IRQ` after the last bit of a matching word.
+    pub fn compile(&self) -> HelperProgram {
+        let cs = (PIN_BASE + self.cs as usize) as u8;
+        HelperProgram {
+            program: self.assemble(),
+            divisor: Some((1, 0)),
+            in_pin_base: (PIN_BASE + self.data as usize) as _,
+            in_shift: ShiftDirection::Left,
+            jmp_pin: cs,
+            preload: None,
+        }
+    }
+
+    /// Returns the number of instructions the receiver program takes.
+    fn len(&self) -> usize {
+        // Assemble into a roomy scratch buffer, the result may not fit PIO memory.
+        self.assemble::<128>().code.len()
+    }
+
+    /// Assembles the receiver program.
+    ///
+    /// # Returns
+    ///
+    /// The program wrapping around the word loop.
+    fn assemble<const N: usize>(&self) -> pio::Program<N> {
+        let cs = (PIN_BASE + self.cs as usize) as u8;
+        let sck = (PIN_BASE + self.sck as usize) as u8;
+        // Modes 0 and 3 sample on the rising edge, modes 1 and 2 on the falling one.
+        let edge = ((self.mode >> 1) == (self.mode & 1)) as u8;
+
+        let mut asm = pio::Assembler::<N>::new();
+        let mut wrap_target = asm.label();
+        let mut wrap_source = asm.label();
+        let mut word = asm.label();
+        let mut bit_loop = asm.label();
+
+        // Transfers within the pre-trigger samples don't count.
+        asm.wait(0, pio::WaitSource::IRQ, PRETRIGGER_IRQ, false);
+        // Wait for chip select, restart whenever it's released between words.
+        asm.bind(&mut wrap_target);
+        asm.wait(0, pio::WaitSource::GPIO, cs, false);
+        asm.bind(&mut word);
+        asm.jmp(pio::JmpCondition::PinHigh, &mut wrap_target);
+
+        // Shift in a word on the sampling edges.
+        asm.mov(
+            pio::MovDestination::ISR,
+            pio::MovOperation::None,
+            pio::MovSource::NULL,
+        );
+        asm.set(pio::SetDestination::X, self.bits - 1);
+        asm.bind(&mut bit_loop);
+        asm.wait(edge ^ 1, pio::WaitSource::GPIO, sck, false);
+        asm.wait(edge, pio::WaitSource::GPIO, sck, false);
+        asm.r#in(pio::InSource::PINS, 1);
+        asm.jmp(pio::JmpCondition::XDecNonZero, &mut bit_loop);
+
+        // Match the word LSB first like a sampled pin vector.
+        asm.mov(
+            pio::MovDestination::OSR,
+            pio::MovOperation::BitReverse,
+            pio::MovSource::ISR,
+        );
+        emit_match(&mut asm, self.mask, self.pattern, &mut word);
+        asm.irq(false, false, TRIGGER_IRQ, false);
+        asm.bind(&mut wrap_source);
+        asm.assemble_with_wrap(wrap_source, wrap_target)
+    }
+}
@@ -1,45 +1,820 @@
 use crate::*;
+use core::sync::atomic::{compiler_fence, Ordering};
+use pio::{Instruction, InstructionOperands, JmpCondition};
 
-/// Type alias for the ingest tuple containing the state machine and transmitter.
-type Ingest = (
-    StateMachine<(pac::PIO0, SM0), Running>,
-    Tx<(pac::PIO0, hal::pio::SM0)>,
+/// Maximum number of segments a segmented capture splits the memory into.
+pub const MAX_SEGMENTS: usize = 32;
+
+/// Type alias for a running state machine with its FIFOs.
+type Machine<SM> = (
+    StateMachine<(pac::PIO0, SM), Running>,
+    Rx<(pac::PIO0, SM)>,
+    Tx<(pac::PIO0, SM)>,
+);
+
+/// Type alias for a configured state machine with its FIFOs, ready to start.
+type Configured<SM> = (
+    StateMachine<(pac::PIO0, SM), Stopped>,
+    Rx<(pac::PIO0, SM)>,
+    Tx<(pac::PIO0, SM)>,
+);
+
+/// Type alias for an uninitialized state machine.
+type Uninit<SM> = UninitStateMachine<(pac::PIO0, SM)>;
+
+/// Type alias for the uninitialized PIO0 state machines.
+pub type StateMachines = (
+    UninitStateMachine<(pac::PIO0, SM0)>,
+    UninitStateMachine<(pac::PIO0, SM1)>,
+    UninitStateMachine<(pac::PIO0, SM2)>,
+    UninitStateMachine<(pac::PIO0, SM3)>,
+);
+
+/// Type alias for the PIO1 block with the streaming and transition state machines.
+pub type Pio1 = (
+    PIO<pac::PIO1>,
+    UninitStateMachine<(pac::PIO1, SM0)>,
+    UninitStateMachine<(pac::PIO1, SM1)>,
 );
 
-/// Enumeration representing the current state of the sink.
-enum Sink {
-    /// Data transfer is in progress with a single buffer.
-    InProgress(
-        single_buffer::Transfer<
-            Channel<CH11>,
-            Rx<(pac::PIO0, SM0)>,
-            &'static mut [u32; SAMPLE_MEMORY / 4],
-        >,
+/// Enumeration representing a helper state machine evaluating a trigger OR group.
+enum Helper<SM: StateMachineIndex> {
+    /// State machine is not running any program.
+    Idle(UninitStateMachine<(pac::PIO0, SM)>),
+    /// State machine is evaluating a trigger program, installed at the given
+    /// offset with the word preloaded into its TX FIFO.
+    Active(
+        StateMachine<(pac::PIO0, SM), Running>,
+        Rx<(pac::PIO0, SM)>,
+        Tx<(pac::PIO0, SM)>,
+        (u8, Option<u32>),
+    ),
+}
+
+impl<SM: StateMachineIndex> Helper<SM> {
+    /// Starts a helper state machine with the given program.
+    ///
+    /// # Arguments
+    ///
+    /// * `sm` - Uninitialized state machine to run the program on.
+    /// * `pio` - PIO instance to install the program into.
+    /// * `program` - Helper program, or `None` to leave the state machine idle.
+    /// * `divisor` - Clock divisor of the capture state machine.
+    ///
+    /// # Returns
+    ///
+    /// A new `Helper` instance.
+    fn start(
+        sm: UninitStateMachine<(pac::PIO0, SM)>,
+        pio: &mut PIO<pac::PIO0>,
+        program: Option<HelperProgram>,
+        divisor: u16,
+    ) -> Self {
+        match program {
+            Some(helper) => {
+                let program = pio.install(&helper.program).unwrap();
+                let offset = program.offset();
+                let (divisor, frac) = helper.divisor.unwrap_or((divisor, 0));
+                let (sm, rx, mut tx) = PIOBuilder::from_installed_program(program)
+                    .out_shift_direction(ShiftDirection::Left)
+                    .in_shift_direction(helper.in_shift)
+                    .clock_divisor_fixed_point(divisor, frac)
+                    .in_pin_base(helper.in_pin_base)
+                    .jmp_pin(helper.jmp_pin)
+                    .build(sm);
+                if let Some(word) = helper.preload {
+                    tx.write(word);
+                }
+                Helper::Active(sm.start(), rx, tx, (offset, helper.preload))
+            }
+            None => Helper::Idle(sm),
+        }
+    }
+
+    /// Stops the helper state machine and uninstalls its program.
+    ///
+    /// # Arguments
+    ///
+    /// * `pio` - PIO instance the program was installed into.
+    ///
+    /// # Returns
+    ///
+    /// The uninitialized state machine.
+    fn stop(self, pio: &mut PIO<pac::PIO0>) -> UninitStateMachine<(pac::PIO0, SM)> {
+        match self {
+            Helper::Idle(sm) => sm,
+            Helper::Active(sm, rx, tx, _) => {
+                let (sm, program) = sm.uninit(rx, tx);
+                pio.uninstall(program);
+                sm
+            }
+        }
+    }
+
+    /// Restarts the helper program from its first instruction.
+    fn rearm(&mut self) {
+        if let Helper::Active(sm, _, tx, (offset, preload)) = self {
+            sm.restart();
+            sm.exec_instruction(Instruction {
+                operands: InstructionOperands::JMP {
+                    condition: JmpCondition::Always,
+                    address: *offset,
+                },
+                delay: 0,
+                side_set: None,
+            });
+            if let Some(word) = preload {
+                tx.write(*word);
+            }
+        }
+    }
+}
+
+/// Enumeration representing the transition detector state machine.
+enum Detector {
+    /// State machine is stopped, its program stays installed.
+    Idle(
+        UninitStateMachine<(pac::PIO1, SM1)>,
+        InstalledProgram<pac::PIO1>,
     ),
-    /// Sink is in standby mode, ready to accept new transfers.
-    StandBy(
-        (
-            Channel<CH11>,
-            Rx<(pac::PIO0, SM0)>,
-            &'static mut [u32; SAMPLE_MEMORY / 4],
-        ),
+    /// State machine is recording transitions.
+    Active(
+        StateMachine<(pac::PIO1, SM1), Running>,
+        Rx<(pac::PIO1, SM1)>,
+        Tx<(pac::PIO1, SM1)>,
     ),
 }
 
+impl Detector {
+    /// Starts recording transitions.
+    ///
+    /// # Arguments
+    ///
+    /// * `divisor` - Clock divisor of the trigger state machine.
+    ///
+    /// # Returns
+    ///
+    /// The active detector.
+    fn start(self, divisor: u16) -> Self {
+        match self {
+            Detector::Idle(sm, program) => {
+                // One loop per sample period, faster rates are limited to one loop per tick.
+                let (int, frac) = match divisor {
+                    divisor if divisor >= TRANSITION_TICK => (
+                        divisor / TRANSITION_TICK,
+                        ((divisor % TRANSITION_TICK) << 8) / TRANSITION_TICK,
+                    ),
+                    _ => (1, 0),
+                };
+                let (sm, rx, tx) = PIOBuilder::from_installed_program(program)
+                    .in_shift_direction(ShiftDirection::Left)
+                    .clock_divisor_fixed_point(int, frac as u8)
+                    .in_pin_base(PIN_BASE as _)
+                    .buffers(Buffers::OnlyRx)
+                    .build(sm);
+                Detector::Active(sm.start(), rx, tx)
+            }
+            active => active,
+        }
+    }
+
+    /// Stops recording transitions.
+    ///
+    /// # Arguments
+    ///
+    /// * `flush` - Called with each record left in the FIFO.
+    ///
+    /// # Returns
+    ///
+    /// The idle detector.
+    fn stop(self, mut flush: impl FnMut(u32)) -> Self {
+        match self {
+            Detector::Active(sm, mut rx, tx) => {
+                let sm = sm.stop();
+                while let Some(record) = rx.read() {
+                    flush(record);
+                }
+                let (sm, program) = sm.uninit(rx, tx);
+                Detector::Idle(sm, program)
+            }
+            idle => idle,
+        }
+    }
+}
+
+/// Returns the sample memory reserved by the linker script.
+///
+/// The `.samples` section in `memory.x` spans all RAM left between the
+/// statics and the stack, so the capture depth follows the firmware size.
+/// Must only be called once.
+///
+/// # Returns
+///
+/// The sample memory, not initialized.
+fn sample_memory() -> &'static mut [u32] {
+    extern "C" {
+        static mut _samples_start: u32;
+        static mut _samples_end: u32;
+    }
+    unsafe {
+        let start = core::ptr::addr_of_mut!(_samples_start);
+        let end = core::ptr::addr_of_mut!(_samples_end);
+        core::slice::from_raw_parts_mut(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Enumeration of the ways the data channel fills memory.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Fill {
+    /// Fills the sample memory once.
+    Once,
+    /// Wraps around the start of the sample memory.
+    Wrap,
+    /// Wraps around the staging ring at the given address core1 compresses from.
+    Staging(u32),
+}
+
+/// Struct representing the DMA channels filling the sample ring buffer.
+///
+/// The data channel moves samples from the capture state machine into the
+/// ring and chains to the rewind channel, which points it back to the start
+/// of the ring and retriggers it. The stop channel is paced by the trigger
+/// state machine and pauses the data channel once the post-trigger samples
+/// are in, its completion raises `DMA_IRQ_0`. Transition records fill the
+/// memory once instead, the data channel raises `DMA_IRQ_0` when it's full.
+/// While compressing, the data channel wraps around the staging ring by
+/// itself and runs until stopped. The stop channel may chain to the analog
+/// capture, halting it on the same event.
+struct Ring {
+    /// Channel moving samples into the ring.
+    data: Channel<CH11>,
+    /// Channel rewinding the data channel to the start of the ring.
+    rewind: Channel<CH10>,
+    /// Channel pausing the data channel.
+    stop: Channel<CH9>,
+    /// Sample memory, the ring occupies its beginning.
+    memory: &'static mut [u32],
+    /// Words copied by the rewind and stop channels: the ring start address
+    /// and the data channel control with the enable bit cleared.
+    control: &'static mut [u32; 2],
+    /// Number of words in the ring.
+    len: usize,
+    /// Channel the stop channel starts once the ring stops.
+    halt: Option<u8>,
+}
+
+impl Ring {
+    /// Aborts the ring transfers and clears a pending stop interrupt.
+    fn abort(&mut self) {
+        // Disable the channels first so the chain can't retrigger an aborted one.
+        for ch in [self.data.ch(), self.rewind.ch(), self.stop.ch()] {
+            ch.ch_al1_ctrl().modify(|_, w| w.en().clear_bit());
+        }
+        let mask = 1 << self.data.id() | 1 << self.rewind.id() | 1 << self.stop.id();
+        unsafe {
+            let dma = &*pac::DMA::ptr();
+            dma.chan_abort().write(|w| w.bits(mask));
+            while dma.chan_abort().read().bits() != 0 {}
+        }
+        self.stop.check_irq0();
+        self.data.check_irq0();
+    }
+
+    /// Starts filling the ring from the capture state machine.
+    ///
+    /// # Arguments
+    ///
+    /// * `capture` - RX FIFO address and DREQ of the state machine filling the ring.
+    /// * `trigger` - RX FIFO of the trigger state machine, pacing the stop channel.
+    /// * `len` - Number of words in the ring.
+    /// * `fill` - How the data channel fills memory.
+    fn start(
+        &mut self,
+        capture: (u32, u8),
+        trigger: &Rx<(pac::PIO0, SM1)>,
+        len: usize,
+        fill: Fill,
+    ) {
+        self.abort();
+        self.len = len;
+        self.control[0] = self.memory.as_ptr() as u32;
+
+        let data = self.data.ch();
+        let (start, ring_size, count) = match fill {
+            // The write address wraps at the staging ring size, the count tells core1 the progress.
+            Fill::Staging(address) => (address, (4 * STAGING).trailing_zeros(), u32::MAX),
+            _ => (self.control[0], 0, len as u32),
+        };
+        unsafe {
+            data.ch_read_addr().write(|w| w.bits(capture.0));
+            data.ch_write_addr().write(|w| w.bits(start));
+            data.ch_al1_ctrl().write(|w| {
+                w.data_size()
+                    .size_word()
+                    .incr_write()
+                    .set_bit()
+                    .ring_sel()
+                    .set_bit()
+                    .ring_size()
+                    .bits(ring_size as u8)
+                    .treq_sel()
+                    .bits(capture.1)
+                    .chain_to()
+                    .bits(match fill {
+                        Fill::Wrap => self.rewind.id(),
+                        _ => self.data.id(),
+                    })
+                    .irq_quiet()
+                    .bit(fill != Fill::Once)
+                    .en()
+                    .set_bit()
+            });
+        }
+        self.control[1] = data.ch_al1_ctrl().read().bits() & !1;
+
+        let rewind = self.rewind.ch();
+        unsafe {
+            rewind
+                .ch_read_addr()
+                .write(|w| w.bits(&self.control[0] as *const u32 as u32));
+            rewind
+                .ch_write_addr()
+                .write(|w| w.bits(data.ch_al2_write_addr_trig().as_ptr() as u32));
+            rewind.ch_trans_count().write(|w| w.bits(1));
+            rewind.ch_al1_ctrl().write(|w| {
+                w.data_size()
+                    .size_word()
+                    .treq_sel()
+                    .permanent()
+                    .chain_to()
+                    .bits(self.rewind.id())
+                    .irq_quiet()
+                    .set_bit()
+                    .en()
+                    .set_bit()
+            });
+        }
+
+        let stop = self.stop.ch();
+        unsafe {
+            stop.ch_read_addr()
+                .write(|w| w.bits(&self.control[1] as *const u32 as u32));
+            stop.ch_write_addr()
+                .write(|w| w.bits(data.ch_al1_ctrl().as_ptr() as u32));
+            stop.ch_trans_count().write(|w| w.bits(1));
+            stop.ch_ctrl_trig().write(|w| {
+                w.data_size()
+                    .size_word()
+                    .treq_sel()
+                    .bits(trigger.dreq_value())
+                    .chain_to()
+                    .bits(self.halt.unwrap_or(self.stop.id()))
+                    .en()
+                    .set_bit()
+            });
+        }
+
+        // Nothing moves until the capture state machine starts pushing samples.
+        data.ch_al1_trans_count_trig()
+            .write(|w| unsafe { w.bits(count) });
+    }
+
+    /// Returns the index of the oldest word once the ring is stopped.
+    fn oldest(&self) -> usize {
+        // Keep sample reads after the DMA has stopped.
+        compiler_fence(Ordering::SeqCst);
+        let next = self.data.ch().ch_write_addr().read().bits() - self.control[0];
+        next as usize / 4 % self.len
+    }
+
+    /// Moves the ring to another part of the memory and restarts it.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - Index of the first word of the ring in memory.
+    fn advance(&mut self, start: usize) {
+        let dma = unsafe { &*pac::DMA::ptr() };
+        dma.chan_abort()
+            .write(|w| unsafe { w.bits(1 << self.data.id()) });
+        while dma.chan_abort().read().bits() != 0 {}
+
+        self.control[0] = self.memory[start..].as_ptr() as u32;
+        let data = self.data.ch();
+        unsafe {
+            data.ch_write_addr().write(|w| w.bits(self.control[0]));
+            data.ch_al1_ctrl().write(|w| w.bits(self.control[1] | 1));
+            // Pace the stop channel with the trigger state machine again.
+            self.stop
+                .ch()
+                .ch_al1_trans_count_trig()
+                .write(|w| w.bits(1));
+            data.ch_al1_trans_count_trig()
+                .write(|w| w.bits(self.len as u32));
+        }
+    }
+
+    /// Returns the number of words written since the start, for a ring that doesn't wrap.
+    fn written(&self) -> usize {
+        compiler_fence(Ordering::SeqCst);
+        let next = self.data.ch().ch_write_addr().read().bits() - self.control[0];
+        next as usize / 4
+    }
+}
+
+/// Enumeration representing the data an upload sends.
+enum Source {
+    /// Samples from the ring, in chunks of two words newest first.
+    Samples {
+        /// Index of the first word of the ring in memory.
+        start: usize,
+        /// Index of the oldest word in the ring.
+        oldest: usize,
+        /// Number of chunks left to send, the next one is `chunk - 1`.
+        chunk: usize,
+    },
+    /// Samples expanded from transition records, newest first.
+    Expanded {
+        /// Index of the record holding the next sample.
+        record: usize,
+        /// Time of that record in system clocks.
+        time: u64,
+        /// Number of samples left to send, the next one is `sample - 1`.
+        sample: u64,
+        /// Sample period in system clocks.
+        period: u64,
+        /// Detector tick in system clocks.
+        tick: u64,
+    },
+    /// Samples packed narrower than the probes, newest first.
+    Packed {
+        /// Index of the first word of the ring in memory.
+        start: usize,
+        /// Index of the oldest word in the ring.
+        oldest: usize,
+        /// Index of the next sample from the oldest one, plus one.
+        next: usize,
+        /// Number of samples left to send.
+        left: usize,
+        /// Number of bits per sample.
+        width: u8,
+        /// Channel of the lowest sampled bit.
+        base: u8,
+    },
+    /// Samples unpacked from the frames core1 compressed, newest first.
+    Compressed {
+        /// Walker over the frames.
+        unpacker: Unpacker,
+        /// Number of samples left to send, padded with zeros once the frames run out.
+        sample: usize,
+    },
+    /// Samples of the analog capture, newest first.
+    Analog {
+        /// Sample count closing the header, sent ahead of the samples.
+        count: Option<u32>,
+        /// Number of samples sent so far.
+        age: usize,
+        /// Number of samples left to send.
+        left: usize,
+    },
+    /// Transition records as captured, oldest first.
+    Records {
+        /// Index of the next record.
+        next: usize,
+        /// Number of records.
+        count: usize,
+    },
+}
+
+/// Struct tracking the upload of a finished capture.
+struct Upload {
+    /// Data left to send.
+    source: Source,
+    /// Bytes of the chunk being sent.
+    bytes: [u8; 8],
+    /// Number of valid bytes in `bytes`.
+    len: usize,
+    /// Number of bytes of the chunk the serial port accepted.
+    sent: usize,
+}
+
+impl Upload {
+    /// Creates a new upload with nothing sent yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Data to send.
+    ///
+    /// # Returns
+    ///
+    /// A new `Upload` instance.
+    fn new(source: Source) -> Self {
+        Self {
+            source,
+            bytes: [0; 8],
+            len: 0,
+            sent: 0,
+        }
+    }
+
+    /// Appends a sample to the chunk, one byte for each enabled channel group.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - Bytes of the chunk.
+    /// * `len` - Number of valid bytes in the chunk.
+    /// * `value` - Sample to append.
+    /// * `ch_groups` - Channel groups to send.
+    fn put(bytes: &mut [u8; 8], len: &mut usize, value: u16, ch_groups: [bool; 2]) {
+        for (group, byte) in ch_groups.into_iter().zip(value.to_le_bytes()) {
+            if group {
+                bytes[*len] = byte;
+                *len += 1;
+            }
+        }
+    }
+
+    /// Fills the chunk with the next bytes to send.
+    ///
+    /// # Arguments
+    ///
+    /// * `ring` - Ring holding the samples or transition records.
+    /// * `analog` - Analog capture holding the analog samples.
+    /// * `ch_groups` - Channel groups to send.
+    ///
+    /// # Returns
+    ///
+    /// `false` once everything has been sent.
+    fn refill(&mut self, ring: &Ring, analog: &Analog, ch_groups: [bool; 2]) -> bool {
+        self.sent = 0;
+        match &mut self.source {
+            Source::Samples {
+                start,
+                oldest,
+                chunk,
+            } => {
+                if *chunk == 0 {
+                    return false;
+                }
+                *chunk -= 1;
+                self.len = 0;
+                // The newest chunk of an odd ring holds a single word.
+                for idx in [2 * *chunk + 1, 2 * *chunk] {
+                    if idx < ring.len {
+                        // The newer sample of a word sits in its high half.
+                        let word = ring.memory[*start + (*oldest + idx) % ring.len];
+                        for value in [(word >> 16) as u16, word as u16] {
+                            Self::put(&mut self.bytes, &mut self.len, value, ch_groups);
+                        }
+                    }
+                }
+            }
+            Source::Expanded {
+                record,
+                time,
+                sample,
+                period,
+                tick,
+            } => {
+                if *sample == 0 {
+                    return false;
+                }
+                self.len = 0;
+                while self.len < self.bytes.len() && *sample > 0 {
+                    *sample -= 1;
+                    // Step back to the record the sample falls into.
+                    while *sample * *period < *time && *record > 0 {
+                        *time -= transition_ticks(ring.memory[*record]) as u64 * *tick;
+                        *record -= 1;
+                    }
+                    Self::put(
+                        &mut self.bytes,
+                        &mut self.len,
+                        (ring.memory[*record] >> 16) as u16,
+                        ch_groups,
+                    );
+                }
+            }
+            Source::Packed {
+                start,
+                oldest,
+                next,
+                left,
+                width,
+                base,
+            } => {
+                if *left == 0 {
+                    return false;
+                }
+                self.len = 0;
+                let per_word = 32 / *width as usize;
+                while self.len < self.bytes.len() && *left > 0 {
+                    *left -= 1;
+                    *next -= 1;
+                    let word = ring.memory[*start + (*oldest + *next / per_word) % ring.len];
+                    let bits = word >> (*next % per_word * *width as usize) & ((1 << *width) - 1);
+                    Self::put(
+                        &mut self.bytes,
+                        &mut self.len,
+                        (bits << *base) as u16,
+                        ch_groups,
+                    );
+                }
+            }
+            Source::Compressed { unpacker, sample } => {
+                if *sample == 0 {
+                    return false;
+                }
+                self.len = 0;
+                while self.len < self.bytes.len() && *sample > 0 {
+                    *sample -= 1;
+                    let value = unpacker.next(&ring.memory[..]).unwrap_or(0);
+                    Self::put(&mut self.bytes, &mut self.len, value, ch_groups);
+                }
+            }
+            Source::Analog { count, age, left } => {
+                if let Some(count) = count.take() {
+                    self.bytes[..4].copy_from_slice(&count.to_le_bytes());
+                    self.len = 4;
+                    return true;
+                }
+                if *left == 0 {
+                    return false;
+                }
+                // Up to four 12-bit samples per refill, little-endian.
+                let count = (*left).min(4);
+                for idx in 0..count {
+                    let sample = analog.sample(*age + idx).to_le_bytes();
+                    self.bytes[2 * idx..2 * idx + 2].copy_from_slice(&sample);
+                }
+                *age += count;
+                *left -= count;
+                self.len = 2 * count;
+            }
+            Source::Records { next, count } => {
+                if *next == *count {
+                    return false;
+                }
+                self.bytes[..4].copy_from_slice(&ring.memory[*next].to_le_bytes());
+                self.len = 4;
+                *next += 1;
+            }
+        }
+        true
+    }
+}
+
+/// Struct tracking the segments of a segmented capture.
+///
+/// The memory is split into equal segments, each holding the samples around
+/// one trigger. Once a segment is full the ring moves on to the next one and
+/// the trigger re-arms, until all segments are captured.
+struct Segments {
+    /// Number of segments to capture, one or less captures a single ring.
+    count: usize,
+    /// Number of segments the last capture was started with, at least one.
+    armed: usize,
+    /// Number of segments captured so far.
+    done: usize,
+    /// Number of segments uploaded so far.
+    sent: usize,
+    /// Oldest word and trigger time in microseconds of each captured segment.
+    stamps: [(usize, u64); MAX_SEGMENTS],
+    /// Pre- and post-trigger counts loaded for each segment.
+    counts: (u32, u32),
+    /// Duration of the post-trigger samples in microseconds.
+    post_time: u64,
+}
+
+/// Enumeration representing the state of the capture and trigger state machines.
+enum Ingest {
+    /// State machines are not running any program.
+    Idle(Uninit<SM0>, Uninit<SM1>),
+    /// Capture state machine fills the ring while the trigger state machine waits for a match.
+    Active(Machine<SM0>, Machine<SM1>),
+    /// Transition detector fills the ring while the trigger state machine times the capture.
+    Transitions(Uninit<SM0>, Machine<SM1>),
+}
+
+impl Ingest {
+    /// Stops the state machines and uninstalls the capture program.
+    ///
+    /// # Arguments
+    ///
+    /// * `pio` - PIO instance the programs were installed into.
+    ///
+    /// # Returns
+    ///
+    /// The uninitialized capture and trigger state machines, and the trigger
+    /// program left installed for the next capture.
+    fn stop(
+        self,
+        pio: &mut PIO<pac::PIO0>,
+    ) -> (
+        Uninit<SM0>,
+        Uninit<SM1>,
+        Option<InstalledProgram<pac::PIO0>>,
+    ) {
+        match self {
+            Ingest::Idle(sm0, sm1) => (sm0, sm1, None),
+            Ingest::Active((sm0, rx0, tx0), (sm1, rx1, tx1)) => {
+                let (sm0, capture) = sm0.uninit(rx0, tx0);
+                let (sm1, trigger) = sm1.uninit(rx1, tx1);
+                pio.uninstall(capture);
+                (sm0, sm1, Some(trigger))
+            }
+            Ingest::Transitions(sm0, (sm1, rx1, tx1)) => {
+                let (sm1, trigger) = sm1.uninit(rx1, tx1);
+                (sm0, sm1, Some(trigger))
+            }
+        }
+    }
+}
+
+/// Returns whether two trigger programs assemble to the same code.
+///
+/// # Arguments
+///
+/// * `a` - First program.
+/// * `b` - Second program.
+///
+/// # Returns
+///
+/// `true` if the programs can share an installation.
+fn same_program(a: &TriggerProgram, b: &TriggerProgram) -> bool {
+    a.code == b.code
+        && a.origin == b.origin
+        && a.wrap == b.wrap
+        && a.side_set.optional() == b.side_set.optional()
+        && a.side_set.bits() == b.side_set.bits()
+        && a.side_set.pindirs() == b.side_set.pindirs()
+}
+
 /// Struct representing the Sampler responsible for data acquisition.
+///
+/// The capture state machine samples continuously into a DMA ring while the
+/// trigger state machine evaluates the trigger next to it, so samples from
+/// before the trigger are kept. Transition sampling records changes of the
+/// probes only, and compression on core1 packs runs of equal samples, both
+/// stretching the memory over far longer captures.
 pub struct Sampler {
     /// PIO instance used for programmable I/O.
     pio: PIO<pac::PIO0>,
-    /// Current sink state.
-    sink: Option<Sink>,
-    /// Ingest tuple containing the state machine and transmitter.
+    /// DMA ring buffer holding the samples.
+    ring: Ring,
+    /// Capture and trigger state machines.
     ingest: Option<Ingest>,
+    /// Upload of the last capture in progress.
+    upload: Option<Upload>,
+    /// Helper state machines evaluating trigger OR groups.
+    helpers: Option<(Helper<SM2>, Helper<SM3>)>,
     /// Divisor used for sampling rate control.
-    divisor: u16,
+    divisor: u32,
+    /// Whether the divisor counts against the doubled SUMP reference clock.
+    demux: bool,
+    /// Sampling rate in Hertz set directly, zero follows the divisor.
+    rate: u32,
     /// Number of samples to read.
     samples: usize,
+    /// Number of samples to capture after the trigger.
+    delay: usize,
     /// Grouping flags for channels.
     ch_groups: [bool; 2],
+    /// Address of the first trigger program instruction after a match.
+    trigger_addr: u8,
+    /// Trigger program last installed, kept installed while it doesn't change.
+    compiled: Option<TriggerProgram>,
+    /// Installation of the compiled trigger program while the trigger state machine is stopped.
+    installed: Option<InstalledProgram<pac::PIO0>>,
+    /// Continuous streaming capture.
+    stream: Stream,
+    /// Whether arming starts a streaming session instead of a triggered capture.
+    streaming: bool,
+    /// Transition detector state machine.
+    detector: Option<Detector>,
+    /// Whether captures record transitions instead of every sample.
+    transitions: bool,
+    /// Whether transition records are sent as captured instead of expanded.
+    records: bool,
+    /// Number of samples a transition capture spans, zero follows the read count.
+    span: u32,
+    /// Compression loop on core1.
+    compressor: Compressor,
+    /// Whether captures are compressed on core1 at rates it keeps up with.
+    compress: bool,
+    /// Number of channels to capture from the enabled group, zero captures all.
+    channels: u8,
+    /// Bits per sample, channel of the lowest bit and number of samples of the last capture.
+    packing: (u8, u8, usize),
+    /// Segments of a segmented capture.
+    segments: Segments,
+    /// Analog capture next to the digital one.
+    analog: Analog,
+    /// Whether the analog samples are sent once the upload in progress completes.
+    analog_queued: bool,
+    /// Whether the following captures bypass the input synchronizers of the probes.
+    sync_bypass: bool,
+    /// Whether the last capture bypassed the input synchronizers.
+    bypassed: bool,
 }
 
 impl Sampler {
@@ -48,49 +823,95 @@ impl Sampler {
     /// # Arguments
     ///
     /// * `pio` - PIO instance for programmable I/O.
-    /// * `sm` - Uninitialized state machine for PIO.
+    /// * `sm` - Uninitialized state machines for PIO.
+    /// * `pio1` - PIO1 instance with the state machines for streaming and transition sampling.
     /// * `dma` - DMA channels for data transfer.
+    /// * `core1` - Peripherals needed to start the compression loop on core1.
+    /// * `adc` - ADC with its inputs for the analog capture.
     ///
     /// # Returns
     ///
     /// A new `Sampler` instance.
     pub fn new(
         pio: PIO<pac::PIO0>,
-        sm: UninitStateMachine<(pac::PIO0, SM0)>,
+        sm: StateMachines,
+        pio1: Pio1,
         dma: dma::Channels,
+        core1: Core1,
+        adc: (hal::adc::Adc, AnalogPins),
     ) -> Self {
-        let mut dma_ch = dma.ch11;
-        dma_ch.enable_irq0();
-
-        let mut pio = pio;
-        let mut asm = TriggerAssembler::new();
-        asm.push(true, true);
-        let program = pio.install(&asm.assemble_program()).unwrap();
-        let (sm, rx, tx) = PIOBuilder::from_installed_program(program)
-            .out_shift_direction(ShiftDirection::Left)
-            .build(sm);
-        let sm = sm.start();
+        let analog = Analog::new(adc.0, adc.1, (dma.ch5, dma.ch6));
+        let (sm0, sm1, sm2, sm3) = sm;
+        let (mut pio1, stream_sm, detector_sm) = pio1;
+        let stream = Stream::new(&mut pio1, stream_sm, (dma.ch7, dma.ch8));
+        let program = pio1.install(&transition_program()).unwrap();
+        let mut data = dma.ch11;
+        data.enable_irq0();
+        let mut stop = dma.ch9;
+        stop.enable_irq0();
 
-        // Allocate memory for sample storage using a singleton.
-        let samples = singleton!(: [u32; SAMPLE_MEMORY / 4] = [0x00; SAMPLE_MEMORY / 4]).unwrap();
-        let sink = Sink::StandBy((dma_ch, rx, samples));
+        // Take the memory the linker script leaves between the statics and the stack.
+        let memory = sample_memory();
+        let control = singleton!(: [u32; 2] = [0x00; 2]).unwrap();
+        let compressor = Compressor::new(core1, &mut memory[..]);
+        let ring = Ring {
+            data,
+            rewind: dma.ch10,
+            stop,
+            memory,
+            control,
+            len: 0,
+            halt: None,
+        };
 
         Self {
             pio,
+            ring,
             divisor: 0,
+            demux: false,
+            rate: 0,
             samples: 0,
+            delay: 0,
             ch_groups: [false; 2],
-            ingest: Some((sm, tx)),
-            sink: Some(sink),
+            trigger_addr: 0,
+            compiled: None,
+            installed: None,
+            ingest: Some(Ingest::Idle(sm0, sm1)),
+            upload: None,
+            helpers: Some((Helper::Idle(sm2), Helper::Idle(sm3))),
+            stream,
+            streaming: false,
+            detector: Some(Detector::Idle(detector_sm, program)),
+            transitions: false,
+            records: false,
+            span: 0,
+            compressor,
+            compress: false,
+            channels: 0,
+            packing: (PROBES as _, 0, 0),
+            segments: Segments {
+                count: 0,
+                armed: 1,
+                done: 0,
+                sent: 0,
+                stamps: [(0, 0); MAX_SEGMENTS],
+                counts: (0, 0),
+                post_time: 0,
+            },
+            analog,
+            analog_queued: false,
+            sync_bypass: false,
+            bypassed: false,
         }
     }
 
-    /// Sets the configuration flags for channel groups.
+    /// Sets the configuration flags for channel groups and demux mode.
     ///
     /// # Arguments
     ///
     /// * `flags` - Bitmask representing the configuration flags.
     pub fn set_flags(&mut self, flags: u8) {
+        self.demux = flags & 1 == 1;
         self.ch_groups[0] = flags >> 2 & 1 == 0;
         self.ch_groups[1] = flags >> 3 & 1 == 0;
     }
@@ -100,8 +921,31 @@ impl Sampler {
     /// # Arguments
     ///
     /// * `divisor` - The divisor value to set.
-    pub fn set_divisor(&mut self, divisor: u16) {
+    pub fn set_divisor(&mut self, divisor: u32) {
         self.divisor = divisor;
+        self.rate = 0;
+    }
+
+    /// Sets the sampling rate directly instead of through the SUMP divisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - Sampling rate in Hertz, zero follows the divisor again.
+    pub fn set_rate(&mut self, rate: u32) {
+        self.rate = rate;
+    }
+
+    /// Returns the requested sampling rate.
+    ///
+    /// # Returns
+    ///
+    /// Sampling rate in Hertz as a numerator and denominator, SUMP hosts
+    /// divide the 100 MHz reference clock, or twice that in demux mode.
+    pub fn rate(&self) -> (u64, u64) {
+        match self.rate {
+            0 => sump_rate(self.divisor, self.demux),
+            rate => (rate as u64, 1),
+        }
     }
 
     /// Sets the number of samples to store in memory.
@@ -113,82 +957,747 @@ impl Sampler {
         self.samples = samples;
     }
 
+    /// Sets the number of samples to capture after the trigger.
+    ///
+    /// The remaining samples are taken from before the trigger.
+    ///
+    /// # Arguments
+    ///
+    /// * `delay` - The number of samples to capture after the trigger.
+    pub fn set_delay_count(&mut self, delay: usize) {
+        self.delay = delay;
+    }
+
+    /// Selects transition sampling and how its records are sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `flags` - Bit 0 enables transition sampling, bit 1 sends the records
+    ///   instead of expanding them into samples.
+    pub fn set_transitions(&mut self, flags: u8) {
+        self.transitions = flags & 1 == 1;
+        self.records = flags & 2 != 0;
+    }
+
+    /// Sets the number of samples a transition capture spans.
+    ///
+    /// # Arguments
+    ///
+    /// * `span` - The number of samples, zero follows the read count.
+    pub fn set_span(&mut self, span: u32) {
+        self.span = span;
+    }
+
+    /// Limits captures to the first channels of the enabled channel group.
+    ///
+    /// # Arguments
+    ///
+    /// * `channels` - Number of channels, zero captures all of them.
+    pub fn set_channels(&mut self, channels: u8) {
+        self.channels = channels;
+    }
+
+    /// Returns how samples are packed for the enabled channels.
+    ///
+    /// A single channel group takes 8 bits per sample, fewer channels round
+    /// up to 4, 2 or 1 bits.
+    ///
+    /// # Returns
+    ///
+    /// The number of bits per sample and the channel of the lowest bit.
+    fn width(&self) -> (u8, u8) {
+        let limit = match self.channels {
+            0 => PROBES as u8,
+            channels => channels.next_power_of_two().min(PROBES as u8),
+        };
+        match self.ch_groups {
+            [true, true] => (limit, 0),
+            [false, true] => (limit.min(8), 8),
+            _ => (limit.min(8), 0),
+        }
+    }
+
+    /// Returns the size of the sample memory.
+    ///
+    /// # Returns
+    ///
+    /// The sample memory size in bytes.
+    pub fn memory_size(&self) -> u32 {
+        4 * self.ring.memory.len() as u32
+    }
+
+    /// Returns the number of samples the memory holds.
+    ///
+    /// # Arguments
+    ///
+    /// * `width` - Number of bits per sample.
+    ///
+    /// # Returns
+    ///
+    /// The capture depth in samples.
+    pub fn depth(&self, width: u8) -> u32 {
+        self.memory_size() * 8 / width as u32
+    }
+
+    /// Sets the number of segments a capture splits the memory into.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - Number of triggered captures per arm, one or less disables segmenting.
+    pub fn set_segments(&mut self, count: usize) {
+        self.segments.count = count.min(MAX_SEGMENTS);
+    }
+
+    /// Selects whether captures are compressed on core1.
+    ///
+    /// # Arguments
+    ///
+    /// * `compress` - Whether to compress captures at rates core1 keeps up with.
+    pub fn set_compression(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    /// Returns how the last capture was stored.
+    ///
+    /// # Returns
+    ///
+    /// The packing with the number of bytes captured and stored.
+    pub fn compression(&self) -> (Packing, u32, u32) {
+        self.compressor.ratio()
+    }
+
+    /// Selects between triggered captures and continuous streaming.
+    ///
+    /// # Arguments
+    ///
+    /// * `streaming` - Whether arming starts a streaming session.
+    pub fn set_streaming(&mut self, streaming: bool) {
+        self.streaming = streaming;
+    }
+
+    /// Checks whether arming starts a streaming session.
+    ///
+    /// # Returns
+    ///
+    /// `true` if streaming is selected.
+    pub fn streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// Checks whether the streaming session was stopped by an overrun.
+    ///
+    /// # Returns
+    ///
+    /// `true` if samples were dropped because USB didn't keep up.
+    pub fn stream_overrun(&self) -> bool {
+        self.stream.overrun()
+    }
+
+    /// Returns the highest sampling rate USB can sustain while streaming.
+    ///
+    /// # Returns
+    ///
+    /// Sampling rate in Hertz for the enabled channel groups.
+    pub fn stream_rate(&self) -> u32 {
+        match self.ch_groups.iter().filter(|group| **group).count() as u32 {
+            0 => sample_rate(),
+            bytes => (STREAM_THROUGHPUT / bytes).min(sample_rate()),
+        }
+    }
+
+    /// Stops the streaming session.
+    pub fn stop_stream(&mut self) {
+        self.stream.stop();
+    }
+
+    /// Checks whether a capture with the specified trigger configuration can start.
+    ///
+    /// # Arguments
+    ///
+    /// * `trigger` - The trigger configuration to use.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the trigger programs don't fit PIO memory.
+    pub fn accepts(&self, trigger: &Trigger) -> bool {
+        // Streaming and transition sampling ignore the trigger.
+        self.streaming || self.transitions || trigger.fits()
+    }
+
     /// Starts the data acquisition process with the specified trigger configuration.
     ///
+    /// The trigger is ignored when streaming is selected.
+    ///
     /// # Arguments
     ///
     /// * `trigger` - The trigger configuration to use.
-    pub fn start(&mut self, trigger: Trigger) {
-        // Retrieve the current DMA channel and PIO resources.
-        let (ch, rx, sample_mem) = match self.sink.take() {
-            Some(Sink::StandBy(dma)) => dma,
-            Some(Sink::InProgress(tx)) => tx.abort(),
-            _ => unreachable!(),
+    ///
+    /// # Returns
+    ///
+    /// `false` if the trigger programs don't fit PIO memory, nothing is started then.
+    pub fn start(&mut self, trigger: Trigger) -> bool {
+        // Refuse before touching anything, the last capture stays as it is.
+        if !self.accepts(&trigger) {
+            return false;
+        }
+
+        // The ring is about to be overwritten, drop an unfinished upload.
+        self.upload = None;
+        // Latch the segments, a later count applies to the next capture only.
+        self.segments.armed = match self.streaming || self.transitions {
+            true => 1,
+            false => self.segments.count.max(1),
         };
+        self.analog_queued = false;
+        // Core1 has to stop reading the data channel before it's reconfigured.
+        self.ring.abort();
+        self.ring.halt = None;
+        self.analog.abort();
+        self.compressor.cancel(&mut self.ring.memory[..]);
 
-        // Initialize the state machine and install the new PIO program based on the trigger.
-        match self.ingest.take() {
-            Some((sm, tx)) => {
-                let (sm, old) = sm.uninit(rx, tx);
-                self.pio.uninstall(old);
-                let program = trigger.compile();
-                let program = self.pio.install(&program).unwrap();
-                let (sm, rx, tx) = PIOBuilder::from_installed_program(program)
-                    .out_shift_direction(ShiftDirection::Left)
-                    .clock_divisor_fixed_point(self.divisor + 1, 0)
-                    .autopush(true)
-                    .in_pin_base(PIN_BASE as _)
-                    .build(sm);
-                let mut transfer = single_buffer::Config::new(ch, rx, sample_mem);
-                transfer.pace(Pace::PreferSource);
-                let transfer = transfer.start();
-                self.sink = Some(Sink::InProgress(transfer));
-                self.ingest = Some((sm.start(), tx));
+        // Stop the state machines, their programs are rebuilt from the new trigger.
+        let (sm0, sm1) = match self.ingest.take() {
+            Some(ingest) => {
+                let (sm0, sm1, installed) = ingest.stop(&mut self.pio);
+                self.installed = installed.or(self.installed.take());
+                (sm0, sm1)
             }
             _ => unreachable!(),
+        };
+        let (sm2, sm3) = match self.helpers.take() {
+            Some((sm2, sm3)) => (sm2.stop(&mut self.pio), sm3.stop(&mut self.pio)),
+            _ => unreachable!(),
+        };
+        self.detector = self.detector.take().map(|detector| detector.stop(|_| {}));
+
+        // Both PIO blocks sample the probes, the detector runs on PIO1.
+        let bypass = match self.sync_bypass {
+            true => ((1 << PROBES) - 1) << PIN_BASE,
+            false => 0,
+        };
+        unsafe {
+            (*pac::PIO0::ptr())
+                .input_sync_bypass()
+                .write(|w| w.bits(bypass));
+            (*pac::PIO1::ptr())
+                .input_sync_bypass()
+                .write(|w| w.bits(bypass));
+        }
+        self.bypassed = self.sync_bypass;
+
+        let divisor = clock_divisor(self.rate());
+        if self.streaming {
+            // Leave the ring alone, the stream has its own state machine.
+            self.ingest = Some(Ingest::Idle(sm0, sm1));
+            self.helpers = Some((Helper::Idle(sm2), Helper::Idle(sm3)));
+            self.stream.start(divisor);
+            return true;
+        }
+        self.stream.stop();
+
+        if self.transitions {
+            // The detector on PIO1 records the probes, the trigger state machine only times the span.
+            let idle = Trigger::default();
+            let (sm, rx, mut tx) = self.build_trigger(sm1, &idle, idle.compile(), divisor);
+            tx.write(0);
+            tx.write(self.span().saturating_sub(1));
+            let detector = self.detector.take().map(|detector| detector.start(divisor));
+            if let Some(Detector::Active(_, capture, _)) = &detector {
+                let capture = (capture.fifo_address() as u32, capture.dreq_value());
+                self.ring
+                    .start(capture, &rx, self.ring.memory.len(), Fill::Once);
+            }
+            self.detector = detector;
+            self.ingest = Some(Ingest::Transitions(sm0, (sm.start(), rx, tx)));
+            self.helpers = Some((Helper::Idle(sm2), Helper::Idle(sm3)));
+            return true;
+        }
+
+        // Compressed captures keep whole samples, the frames pack runs instead.
+        let segments = self.segments.armed;
+        let compress = self.compress && segments == 1 && divisor >= COMPRESS_DIVISOR;
+        let (width, base) = match compress {
+            true => (PROBES as u8, 0),
+            false => self.width(),
+        };
+
+        // The capture program takes a single instruction, install it before the trigger.
+        let mut trigger = trigger;
+        trigger.set_rearm(segments > 1);
+        let compiled = trigger.compile();
+        self.release_trigger(&compiled);
+        let program = self.pio.install(&capture_program(width)).unwrap();
+        let (capture, capture_rx, capture_tx) = PIOBuilder::from_installed_program(program)
+            .clock_divisor_fixed_point(divisor, 0)
+            .autopush(true)
+            .in_pin_base(PIN_BASE as u8 + base)
+            // A deeper FIFO rides out the ring rewinds.
+            .buffers(Buffers::OnlyRx)
+            .build(sm0);
+
+        let (sm, rx, mut tx) = self.build_trigger(sm1, &trigger, compiled, divisor);
+
+        // Each word holds `32 / width` samples, the delay count sets the trigger position.
+        // Compressed captures aren't limited by the memory size, the frames hold what fits.
+        let per_word = 32 / width as usize;
+        // Segments split the memory evenly, each one holds the samples of a trigger.
+        let capacity = self.ring.memory.len() / segments * per_word;
+        let total = match compress {
+            true => 4 * (self.samples + 1),
+            false => (4 * (self.samples + 1)).min(capacity),
+        };
+        let len = total.div_ceil(per_word);
+        self.packing = (width, base, total);
+        let post = (4 * (self.delay + 1)).min(total);
+        let counts = ((total - post).saturating_sub(1) as u32, post as u32 - 1);
+        tx.write(counts.0);
+        tx.write(counts.1);
+        self.segments.done = 0;
+        self.segments.sent = 0;
+        self.segments.counts = counts;
+        self.segments.post_time = post as u64 * divisor as u64 * 1_000_000 / sample_rate() as u64;
+
+        // The analog capture halts with the ring, segments would re-arm without it.
+        let analog = self.analog.enabled() && segments == 1;
+        if analog {
+            self.ring.halt = Some(self.analog.halt_channel());
+        }
+
+        let source = (capture_rx.fifo_address() as u32, capture_rx.dreq_value());
+        match compress {
+            true => {
+                let staging = Fill::Staging(self.compressor.address());
+                self.ring.start(source, &rx, len, staging);
+                self.compressor.start();
+            }
+            false => self.ring.start(source, &rx, len, Fill::Wrap),
+        }
+        self.pio.clear_irq(1 << TRIGGER_IRQ);
+        // Hold the helpers back until the trigger state machine counted the pre-trigger samples.
+        self.pio.force_irq(1 << PRETRIGGER_IRQ);
+        // Start capturing first, the pre-trigger count then covers stored samples.
+        if analog {
+            self.analog.start();
+        }
+        let capture = (capture.start(), capture_rx, capture_tx);
+        self.ingest = Some(Ingest::Active(capture, (sm.start(), rx, tx)));
+
+        // Start helper state machines, they release the trigger via IRQ.
+        let [h2, h3] = trigger.helpers();
+        self.helpers = Some((
+            Helper::start(sm2, &mut self.pio, h2, divisor),
+            Helper::start(sm3, &mut self.pio, h3, divisor),
+        ));
+        true
+    }
+
+    /// Uninstalls the kept trigger program if it differs from the next one.
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - Trigger program of the next capture.
+    fn release_trigger(&mut self, program: &TriggerProgram) {
+        let same = self
+            .compiled
+            .as_ref()
+            .is_some_and(|compiled| same_program(compiled, program));
+        if !same {
+            if let Some(installed) = self.installed.take() {
+                self.pio.uninstall(installed);
+            }
+            self.compiled = None;
         }
     }
 
-    /// Drains the acquired data and sends it over the serial port.
+    /// Installs the trigger program and configures the trigger state machine.
+    ///
+    /// An unchanged program stays installed from the previous capture, so
+    /// re-arming with the same trigger skips the install.
+    ///
+    /// # Arguments
+    ///
+    /// * `sm` - Uninitialized trigger state machine.
+    /// * `trigger` - The trigger configuration.
+    /// * `compiled` - The compiled trigger program.
+    /// * `divisor` - Clock divisor of the capture state machine.
+    ///
+    /// # Returns
+    ///
+    /// The stopped trigger state machine with its FIFOs.
+    fn build_trigger(
+        &mut self,
+        sm: Uninit<SM1>,
+        trigger: &Trigger,
+        compiled: TriggerProgram,
+        divisor: u16,
+    ) -> Configured<SM1> {
+        self.release_trigger(&compiled);
+        let program = match self.installed.take() {
+            Some(installed) => installed,
+            None => {
+                let installed = self.pio.install(&compiled).unwrap();
+                self.compiled = Some(compiled);
+                installed
+            }
+        };
+        self.trigger_addr = program.wrap_target();
+        let output = trigger.output();
+        let (mut sm, rx, tx) = PIOBuilder::from_installed_program(program)
+            .out_shift_direction(ShiftDirection::Left)
+            .clock_divisor_fixed_point(divisor, 0)
+            .in_pin_base(PIN_BASE as _)
+            .side_set_pin_base(output.map_or(0, |out| out.pin()))
+            .build(sm);
+        // The builder keeps the FIFOs, a stop word or counts left by the last
+        // capture would stop the ring at once or shift the counts.
+        sm.clear_fifos();
+        // Release auxiliary pins a previous capture may have driven.
+        sm.set_pindirs(AUX_PINS.map(|pin| (pin, PinDir::Input)));
+        if let Some(out) = output {
+            // Park the trigger output at its idle level before the program runs.
+            sm.set_pins([(out.pin(), out.idle_state())]);
+            sm.set_pindirs([(out.pin(), PinDir::Output)]);
+        }
+        (sm, rx, tx)
+    }
+
+    /// Returns the number of samples a transition capture spans.
+    fn span(&self) -> u32 {
+        match self.span {
+            0 => 4 * (self.samples as u32 + 1),
+            span => span,
+        }
+    }
+
+    /// Checks whether the trigger program has matched.
+    ///
+    /// # Returns
+    ///
+    /// `true` if all trigger stages have matched.
+    pub fn triggered(&self) -> bool {
+        match &self.ingest {
+            Some(Ingest::Active(_, (sm, _, _)) | Ingest::Transitions(_, (sm, _, _))) => {
+                sm.instruction_address() >= self.trigger_addr as u32
+            }
+            _ => false,
+        }
+    }
+
+    /// Forces the trigger regardless of the trigger stages.
+    ///
+    /// Restarting the trigger state machine jumps straight to the
+    /// post-trigger count.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the capture doesn't wait for a trigger, transition sampling ignores it.
+    pub fn force_trigger(&mut self) -> bool {
+        match &mut self.ingest {
+            Some(Ingest::Active(_, (sm, _, _))) => {
+                sm.restart();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Handles a DMA completion: sends a full streaming half, or finishes
+    /// the capture once the ring has stopped and starts the upload.
     ///
     /// # Arguments
     ///
     /// * `serial` - Mutable reference to the serial port for data transmission.
-    pub fn drain(&mut self, serial: &mut SerialPort<'_, UsbBus>) {
-        if let Some(sink) = self.sink.take() {
-            match sink {
-                Sink::StandBy((mut ch, rx, sample_mem)) => {
-                    ch.check_irq0();
-                    self.sink = Some(Sink::StandBy((ch, rx, sample_mem)));
-                }
-                Sink::InProgress(mut tx) => {
-                    tx.check_irq0();
-                    let (ch, rx, sample_mem) = tx.abort();
-                    // Iterate over the sample memory and send data based on channel groups.
-                    for chunk in sample_mem.chunks(2).take(self.samples + 1).rev() {
-                        let s02 = chunk[1].to_le_bytes();
-                        let s13 = chunk[0].to_le_bytes();
-                        match self.ch_groups {
-                            [true, false] => {
-                                // Send specific bits for channel group 0.
-                                serial.write(&[s02[0], s02[2], s13[0], s13[2]]).ok();
-                            }
-                            [false, true] => {
-                                // Send specific bits for channel group 1.
-                                serial.write(&[s02[1], s02[3], s13[1], s13[3]]).ok();
-                            }
-                            [true, true] => {
-                                // Send all bits if both channel groups are active.
-                                serial.write(&s02).ok();
-                                serial.write(&s13).ok();
-                            }
-                            _ => {
-                                // Do not send data if no channel groups are active.
-                            }
-                        }
-                    }
-                    // Return the DMA channel and sample memory to standby.
-                    self.sink = Some(Sink::StandBy((ch, rx, sample_mem)));
+    ///
+    /// # Returns
+    ///
+    /// `true` if a triggered capture has finished.
+    pub fn drain(&mut self, serial: &mut SerialPort<'_, UsbBus>) -> bool {
+        self.stream.advance();
+        self.stream.upload(serial, self.ch_groups);
+        let stopped = self.ring.stop.check_irq0();
+        let full = self.ring.data.check_irq0();
+        if !stopped && !full {
+            return false;
+        }
+        let transitions = matches!(self.ingest, Some(Ingest::Transitions(..)));
+
+        if stopped && !transitions && self.segments.armed > 1 {
+            // Stamp the segment with the time of its trigger.
+            let time = Mono::now().ticks() - self.segments.post_time;
+            self.segments.stamps[self.segments.done] = (self.ring.oldest(), time);
+            self.segments.done += 1;
+            if self.segments.done < self.segments.armed {
+                self.rearm();
+                return false;
+            }
+        }
+
+        // The halt channel stopped the analog capture along with the ring.
+        if stopped {
+            self.analog.finish();
+        }
+
+        // The ring is stopped, release the state machines.
+        if let Some(ingest) = self.ingest.take() {
+            let (sm0, sm1, installed) = ingest.stop(&mut self.pio);
+            self.installed = installed;
+            self.ingest = Some(Ingest::Idle(sm0, sm1));
+        }
+
+        self.upload = match transitions {
+            true => Some(self.transition_upload()),
+            false if self.compressor.active() => Some(Upload::new(Source::Compressed {
+                unpacker: self.compressor.stop(&mut self.ring.memory[..]),
+                sample: 2 * self.ring.len,
+            })),
+            false if self.segments.armed > 1 => self.segment_upload(),
+            false => Some(self.ring_upload(0, self.ring.oldest())),
+        };
+        self.upload(serial);
+        true
+    }
+
+    /// Moves the ring to the next segment and re-arms the trigger.
+    fn rearm(&mut self) {
+        // Samples held up in the FIFO while the ring was paused are stale, and
+        // the stop word of this segment would stop the next one at once.
+        if let Some(Ingest::Active((_, rx0, _), (_, rx1, _))) = &mut self.ingest {
+            while rx0.read().is_some() {}
+            while rx1.read().is_some() {}
+        }
+        self.ring.advance(self.segments.done * self.ring.len);
+        // Hold the helpers back again before they restart, then drop their last match.
+        self.pio.force_irq(1 << PRETRIGGER_IRQ);
+        if let Some((h2, h3)) = &mut self.helpers {
+            h2.rearm();
+            h3.rearm();
+        }
+        self.pio.clear_irq(1 << TRIGGER_IRQ);
+        // The trigger state machine waits for the counts of the next segment.
+        if let Some(Ingest::Active(_, (_, _, tx))) = &mut self.ingest {
+            tx.write(self.segments.counts.0);
+            tx.write(self.segments.counts.1);
+        }
+    }
+
+    /// Prepares the upload of samples from the ring.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - Index of the first word of the ring in memory.
+    /// * `oldest` - Index of the oldest word in the ring.
+    ///
+    /// # Returns
+    ///
+    /// The upload of the samples, newest first.
+    fn ring_upload(&self, start: usize, oldest: usize) -> Upload {
+        match self.packing {
+            (width, base, total) if width < PROBES as u8 => Upload::new(Source::Packed {
+                start,
+                oldest,
+                next: self.ring.len * (32 / width as usize),
+                left: total,
+                width,
+                base,
+            }),
+            // Chunks of two words are sent newest first.
+            _ => Upload::new(Source::Samples {
+                start,
+                oldest,
+                chunk: self.ring.len.div_ceil(2),
+            }),
+        }
+    }
+
+    /// Prepares the upload of the next captured segment.
+    ///
+    /// # Returns
+    ///
+    /// The upload of the segment, its trigger time in microseconds goes
+    /// ahead of the samples. `None` once all segments have been sent.
+    fn segment_upload(&mut self) -> Option<Upload> {
+        let segment = self.segments.sent;
+        if segment == self.segments.done {
+            return None;
+        }
+        self.segments.sent += 1;
+        let (oldest, time) = self.segments.stamps[segment];
+        let mut upload = self.ring_upload(segment * self.ring.len, oldest);
+        upload.bytes = time.to_le_bytes();
+        upload.len = 8;
+        Some(upload)
+    }
+
+    /// Collects the transition records and prepares their upload.
+    ///
+    /// # Returns
+    ///
+    /// The upload of the records, or of the samples expanded from them.
+    fn transition_upload(&mut self) -> Upload {
+        let mut count = self.ring.written();
+        if let Some(detector) = self.detector.take() {
+            // Records still in the FIFO were taken before the ring stopped.
+            let memory = &mut self.ring.memory;
+            self.detector = Some(detector.stop(|record| {
+                if let Some(slot) = memory.get_mut(count) {
+                    *slot = record;
+                    count += 1;
+                }
+            }));
+        }
+
+        if self.records {
+            // The number of records goes ahead of them.
+            let mut upload = Upload::new(Source::Records { next: 0, count });
+            upload.bytes[..4].copy_from_slice(&(count as u32).to_le_bytes());
+            upload.len = 4;
+            return upload;
+        }
+
+        let period = clock_divisor(self.rate()) as u64;
+        let tick = period.max(TRANSITION_TICK as u64);
+        let span = match count {
+            0 => 0,
+            _ => self.span() as u64,
+        };
+        // Find the last record within the span, the upload walks back from there.
+        let (mut record, mut time) = (0, 0);
+        for idx in 1..count {
+            let next = time + transition_ticks(self.ring.memory[idx]) as u64 * tick;
+            if next >= span * period {
+                break;
+            }
+            (record, time) = (idx, next);
+        }
+        Upload::new(Source::Expanded {
+            record,
+            time,
+            sample: span,
+            period,
+            tick,
+        })
+    }
+
+    /// Selects whether the following captures bypass the input synchronizers.
+    ///
+    /// # Arguments
+    ///
+    /// * `bypass` - Whether the probes reach PIO without the 2-flop synchronizers.
+    pub fn set_sync_bypass(&mut self, bypass: bool) {
+        self.sync_bypass = bypass;
+    }
+
+    /// Returns the input synchronizer setting.
+    ///
+    /// # Returns
+    ///
+    /// Whether the following captures and the last one bypass the input synchronizers.
+    pub fn sync_bypass(&self) -> (bool, bool) {
+        (self.sync_bypass, self.bypassed)
+    }
+
+    /// Selects the analog inputs converted along the following captures.
+    ///
+    /// # Arguments
+    ///
+    /// * `inputs` - Bit mask of the inputs, bit 0 for GPIO 26, zero disables the analog capture.
+    pub fn set_analog_inputs(&mut self, inputs: u8) {
+        self.analog.set_inputs(inputs);
+    }
+
+    /// Sets the conversion rate of the analog capture.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - Conversions per second, shared by the enabled inputs.
+    ///
+    /// # Returns
+    ///
+    /// The conversion rate achieved.
+    pub fn set_analog_rate(&mut self, rate: u32) -> u32 {
+        self.analog.set_rate(rate)
+    }
+
+    /// Sends the analog samples of the last capture.
+    ///
+    /// A header with the `u32` conversion rate, the `u8` enabled inputs, the
+    /// `u8` input of the newest sample and the `u32` number of samples goes
+    /// ahead of the samples. While another upload is in progress they are
+    /// queued behind it.
+    ///
+    /// # Arguments
+    ///
+    /// * `serial` - Mutable reference to the serial port for data transmission.
+    pub fn upload_analog(&mut self, serial: &mut SerialPort<'_, UsbBus>) {
+        match self.upload {
+            Some(_) => self.analog_queued = true,
+            None => self.upload = Some(self.analog_upload()),
+        }
+        self.upload(serial);
+    }
+
+    /// Prepares the upload of the analog samples with their header staged.
+    ///
+    /// # Returns
+    ///
+    /// The upload of the analog samples of the last capture.
+    fn analog_upload(&self) -> Upload {
+        let (rate, inputs, newest, kept) = self.analog.captured();
+        let mut upload = Upload::new(Source::Analog {
+            count: Some(kept as u32),
+            age: 0,
+            left: kept,
+        });
+        upload.bytes[..4].copy_from_slice(&rate.to_le_bytes());
+        upload.bytes[4..6].copy_from_slice(&[inputs, newest]);
+        upload.len = 6;
+        upload
+    }
+
+    /// Checks whether the last capture is still being uploaded.
+    ///
+    /// # Returns
+    ///
+    /// `true` until all of its samples have been sent.
+    pub fn uploading(&self) -> bool {
+        self.upload.is_some()
+    }
+
+    /// Sends as much of the streamed samples and the pending upload as the
+    /// serial port accepts.
+    ///
+    /// Called on every USB poll, the upload resumes where the endpoint
+    /// buffer filled up last time.
+    ///
+    /// # Arguments
+    ///
+    /// * `serial` - Mutable reference to the serial port for data transmission.
+    pub fn upload(&mut self, serial: &mut SerialPort<'_, UsbBus>) {
+        self.stream.upload(serial, self.ch_groups);
+        loop {
+            let Some(upload) = &mut self.upload else {
+                return;
+            };
+            if upload.sent == upload.len && !upload.refill(&self.ring, &self.analog, self.ch_groups)
+            {
+                // Segments follow each other, a regular capture ends here.
+                let segment = !matches!(upload.source, Source::Analog { .. });
+                self.upload = match self.segments.armed > 1 && segment {
+                    true => self.segment_upload(),
+                    false => None,
+                };
+                // Queued analog samples follow the last segment.
+                if self.upload.is_none() && self.analog_queued {
+                    self.analog_queued = false;
+                    self.upload = Some(self.analog_upload());
+                }
+                continue;
+            }
+            if upload.sent < upload.len {
+                match serial.write(&upload.bytes[upload.sent..upload.len]) {
+                    Ok(n) => upload.sent += n,
+                    // Endpoint buffer is full, resume on the next poll.
+                    Err(_) => return,
                 }
             }
         }
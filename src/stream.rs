@@ -0,0 +1,290 @@
+use crate::*;
+
+/// Number of words in each half of the streaming buffer.
+pub const STREAM_HALF: usize = 1024;
+/// Sustained USB throughput in bytes per second the stream is rated for.
+pub const STREAM_THROUGHPUT: u32 = 1_000_000;
+
+/// Type alias for one half of the streaming buffer.
+type Half = &'static mut [u32; STREAM_HALF];
+
+/// Type alias for the double-buffered transfer from the capture state machine.
+type Pipe<STATE> =
+    double_buffer::Transfer<Channel<CH7>, Channel<CH8>, Rx<(pac::PIO1, SM0)>, Half, STATE>;
+
+/// Enumeration representing the state of the streaming capture state machine.
+enum Engine {
+    /// State machine is halted between sessions.
+    Stopped(StateMachine<(pac::PIO1, SM0), Stopped>),
+    /// State machine is pushing samples.
+    Running(StateMachine<(pac::PIO1, SM0), Running>),
+}
+
+/// Enumeration representing the state of the double-buffered transfer.
+enum Link {
+    /// The active channel fills a half, the other half is queued behind it.
+    Queued(Pipe<double_buffer::WriteNext<Half>>),
+    /// A full half is being sent from the given byte while the active channel fills the other one.
+    Sending(Pipe<()>, Half, usize),
+    /// The last half filled before an overrun is being sent from the given byte.
+    Draining(Pipe<()>, Half, usize),
+    /// Both channels are idle after an overrun, the half isn't queued.
+    Stalled(Pipe<()>, Half),
+}
+
+/// Struct representing the continuous streaming capture.
+///
+/// A dedicated state machine on PIO1 samples the probes into a pair of
+/// buffer halves. While the DMA fills one half, the other is sent over USB
+/// and queued again. If a half fills up before the other one is queued, the
+/// session stops after sending the samples captured so far and reports an
+/// overrun instead of sending a gap.
+pub struct Stream {
+    /// Streaming capture state machine.
+    engine: Option<Engine>,
+    /// Double-buffered transfer of the samples.
+    link: Option<Link>,
+    /// Number of stale words at the start of the next full half.
+    skip: usize,
+    /// Whether CH8 is the active channel of the transfer, the one filling or armed to fill next.
+    second: bool,
+    /// Whether the last session was stopped by an overrun.
+    overrun: bool,
+}
+
+impl Stream {
+    /// Creates a new instance of the Stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `pio` - PIO instance to install the capture program into.
+    /// * `sm` - State machine dedicated to streaming.
+    /// * `ch` - DMA channels alternating between the buffer halves.
+    ///
+    /// # Returns
+    ///
+    /// A new `Stream` instance.
+    pub fn new(
+        pio: &mut PIO<pac::PIO1>,
+        sm: UninitStateMachine<(pac::PIO1, SM0)>,
+        ch: (Channel<CH7>, Channel<CH8>),
+    ) -> Self {
+        // The capture program stays installed for good, sessions only stop and start it.
+        let program = pio.install(&capture_program(PROBES as _)).unwrap();
+        let (sm, rx, _) = PIOBuilder::from_installed_program(program)
+            .autopush(true)
+            .in_pin_base(PIN_BASE as _)
+            .buffers(Buffers::OnlyRx)
+            .build(sm);
+
+        let (mut ch7, mut ch8) = ch;
+        ch7.enable_irq0();
+        ch8.enable_irq0();
+
+        let first = singleton!(: [u32; STREAM_HALF] = [0x00; STREAM_HALF]).unwrap();
+        let second = singleton!(: [u32; STREAM_HALF] = [0x00; STREAM_HALF]).unwrap();
+        // Nothing moves until the state machine starts pushing samples.
+        let pipe = double_buffer::Config::new((ch7, ch8), rx, first)
+            .start()
+            .write_next(second);
+
+        Self {
+            engine: Some(Engine::Stopped(sm)),
+            link: Some(Link::Queued(pipe)),
+            skip: 0,
+            second: false,
+            overrun: false,
+        }
+    }
+
+    /// Checks whether the last session was stopped by an overrun.
+    ///
+    /// # Returns
+    ///
+    /// `true` if samples were dropped because USB didn't keep up.
+    pub fn overrun(&self) -> bool {
+        self.overrun
+    }
+
+    /// Starts a new streaming session.
+    ///
+    /// # Arguments
+    ///
+    /// * `divisor` - Clock divisor of the streaming state machine.
+    pub fn start(&mut self, divisor: u16) {
+        self.stop();
+
+        self.link = match self.link.take() {
+            Some(Link::Stalled(pipe, half)) => {
+                // The active channel was armed too late to be chained and never triggered.
+                // Its transfer count reads zero like the finished one, so it's tracked instead.
+                let (id, _) = Self::channels()[self.second as usize];
+                let pipe = pipe.write_next(half);
+                let dma = unsafe { &*pac::DMA::ptr() };
+                dma.multi_chan_trigger()
+                    .write(|w| unsafe { w.bits(1 << id) });
+                Some(Link::Queued(pipe))
+            }
+            link => link,
+        };
+
+        // Skip the words the previous session left in the half being filled.
+        self.skip = Self::channels()
+            .iter()
+            .find(|(_, ch)| ch.ch_ctrl_trig().read().busy().bit_is_set())
+            .map_or(0, |(_, ch)| {
+                STREAM_HALF - ch.ch_trans_count().read().bits() as usize
+            });
+        self.overrun = false;
+
+        self.engine = self.engine.take().map(|engine| match engine {
+            Engine::Stopped(mut sm) => {
+                sm.clock_divisor_fixed_point(divisor, 0);
+                let mut sm = sm.start();
+                // Drop a half-shifted word left in the ISR.
+                sm.restart();
+                Engine::Running(sm)
+            }
+            engine => engine,
+        });
+    }
+
+    /// Stops the streaming session, samples not sent yet are dropped.
+    pub fn stop(&mut self) {
+        self.halt();
+        self.advance();
+        self.link = match self.link.take() {
+            Some(Link::Sending(pipe, half, _)) if self.overrun => {
+                let (half, pipe) = pipe.write_next(half).wait();
+                self.second = !self.second;
+                Some(Link::Stalled(pipe, half))
+            }
+            Some(Link::Sending(pipe, half, _)) => Some(Link::Queued(pipe.write_next(half))),
+            Some(Link::Draining(pipe, half, _)) => Some(Link::Stalled(pipe, half)),
+            link => link,
+        };
+    }
+
+    /// Handles the completion of a buffer half.
+    ///
+    /// Called from the DMA interrupt, a full half is handed over for sending
+    /// and an overrun stops the state machine.
+    pub fn advance(&mut self) {
+        self.link = match self.link.take() {
+            Some(Link::Queued(mut pipe)) => {
+                if !pipe.check_irq0() {
+                    Some(Link::Queued(pipe))
+                } else {
+                    let (half, pipe) = pipe.wait();
+                    self.second = !self.second;
+                    let start = core::mem::take(&mut self.skip) * 4;
+                    if pipe.is_done() {
+                        // The next half was queued too late to be chained.
+                        self.halt();
+                        self.overrun = true;
+                        Some(Link::Draining(pipe, half, start))
+                    } else {
+                        Some(Link::Sending(pipe, half, start))
+                    }
+                }
+            }
+            Some(Link::Sending(mut pipe, half, pos)) => {
+                if pipe.check_irq0() {
+                    // The other half filled up before this one was sent.
+                    self.halt();
+                    self.overrun = true;
+                }
+                Some(Link::Sending(pipe, half, pos))
+            }
+            link => link,
+        };
+    }
+
+    /// Sends as much of the full half as the serial port accepts.
+    ///
+    /// Samples are sent in capture order, one byte per sample for each
+    /// enabled channel group.
+    ///
+    /// # Arguments
+    ///
+    /// * `serial` - Mutable reference to the serial port for data transmission.
+    /// * `ch_groups` - Channel groups to send.
+    pub fn upload(&mut self, serial: &mut SerialPort<'_, UsbBus>, ch_groups: [bool; 2]) {
+        // Bytes alternate between channel groups 0 and 1.
+        let keep = |byte: usize| ch_groups[byte % 2];
+        let mut bytes = [0; 64];
+        loop {
+            let (half, pos) = match &mut self.link {
+                Some(Link::Sending(_, half, pos) | Link::Draining(_, half, pos)) => (half, pos),
+                _ => return,
+            };
+            if *pos == 4 * STREAM_HALF {
+                self.finish();
+                continue;
+            }
+
+            let mut len = 0;
+            let mut end = *pos;
+            while len < bytes.len() && end < 4 * STREAM_HALF {
+                if keep(end) {
+                    bytes[len] = half[end / 4].to_le_bytes()[end % 4];
+                    len += 1;
+                }
+                end += 1;
+            }
+            if len == 0 {
+                // Do not send data if no channel groups are active.
+                *pos = end;
+                continue;
+            }
+
+            match serial.write(&bytes[..len]) {
+                Ok(mut n) => {
+                    while n > 0 {
+                        if keep(*pos) {
+                            n -= 1;
+                        }
+                        *pos += 1;
+                    }
+                }
+                // Endpoint buffer is full, resume on the next poll.
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Queues the half that has been sent again.
+    ///
+    /// After an overrun the half filled before it is sent next, then the
+    /// session ends.
+    fn finish(&mut self) {
+        self.link = match self.link.take() {
+            Some(Link::Sending(pipe, half, _)) if self.overrun => {
+                let (half, pipe) = pipe.write_next(half).wait();
+                self.second = !self.second;
+                Some(Link::Draining(pipe, half, 0))
+            }
+            Some(Link::Sending(pipe, half, _)) => Some(Link::Queued(pipe.write_next(half))),
+            Some(Link::Draining(pipe, half, _)) => Some(Link::Stalled(pipe, half)),
+            link => link,
+        };
+    }
+
+    /// Stops the state machine and drops the samples left in its FIFO.
+    fn halt(&mut self) {
+        self.engine = self.engine.take().map(|engine| match engine {
+            Engine::Running(sm) => {
+                let mut sm = sm.stop();
+                sm.clear_fifos();
+                Engine::Stopped(sm)
+            }
+            engine => engine,
+        });
+    }
+
+    /// Returns the register blocks of the streaming DMA channels.
+    fn channels() -> [(u8, &'static pac::dma::CH); 2] {
+        let dma = unsafe { &*pac::DMA::ptr() };
+        [CH7::id(), CH8::id()].map(|id| (id, dma.ch(id as usize)))
+    }
+}
@@ -6,8 +6,47 @@ pub type TriggerAssembler = pio::Assembler<32>;
 /// Type alias for the compiled PIO trigger program.
 pub type TriggerProgram = pio::Program<32>;
 
+/// Number of helper state machines evaluating OR groups and protocol triggers.
+pub const TRIGGER_GROUPS: usize = 2;
+
+/// PIO IRQ flag helper state machines raise to release the trigger state machine.
+pub const TRIGGER_IRQ: u8 = 0;
+
+/// PIO IRQ flag holding the helper state machines back while the pre-trigger samples fill the ring.
+///
+/// Set when arming, the trigger state machine clears it once its countdown ends.
+pub const PRETRIGGER_IRQ: u8 = 1;
+
+/// System clocks per iteration of the transition detector loop.
+pub const TRANSITION_TICK: u16 = 7;
+
+/// Number of instructions in PIO memory, shared by all programs of a block.
+pub const PIO_LEN: usize = pio::RP2040_MAX_PROGRAM_SIZE;
+
+/// Maximum number of instructions a bus range comparison may take.
+///
+/// Leaves room for the capture loop and the pre- and post-trigger counters.
+pub const RANGE_BUDGET: usize = 20;
+
+/// Struct representing a program for a helper state machine with its settings.
+pub struct HelperProgram {
+    /// Compiled PIO program raising `TRIGGER_IRQ` on match.
+    pub program: TriggerProgram,
+    /// Clock divisor as integer and fractional parts, `None` to run at the sampling rate.
+    pub divisor: Option<(u16, u8)>,
+    /// GPIO number of the first input pin.
+    pub in_pin_base: u8,
+    /// Input shift direction.
+    pub in_shift: ShiftDirection,
+    /// GPIO number tested by `jmp pin`.
+    pub jmp_pin: u8,
+    /// Word pushed into the TX FIFO before the program starts.
+    pub preload: Option<u32>,
+}
+
 /// Struct representing a single trigger stage with mask, pattern, and delay.
 #[derive(Default, Clone, Copy)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
 pub struct TriggerStage {
     /// Bitmask for the trigger condition.
     mask: u32,
@@ -15,6 +54,136 @@ pub struct TriggerStage {
     pattern: u32,
     /// Delay before the trigger is activated.
     delay: u32,
+    /// Number of pattern occurrences required before the stage advances.
+    count: u32,
+    /// Sample clocks the next stage has to match within, zero waits forever.
+    window: u32,
+    /// Sample clocks the pattern has to stay asserted for, zero advances on the first match.
+    hold: u32,
+    /// Optional bus value range condition.
+    range: Option<BusRange>,
+    /// Optional external trigger input condition.
+    external: Option<ExternalTrigger>,
+    /// OR group the stage belongs to.
+    group: u8,
+}
+
+impl TriggerStage {
+    /// Checks whether the stage has any condition to wait for.
+    fn is_active(&self) -> bool {
+        self.has_pattern() || self.external.is_some()
+    }
+
+    /// Checks whether the stage looks at the sampled pins.
+    fn has_pattern(&self) -> bool {
+        self.mask != 0 || self.range.is_some()
+    }
+
+    /// Checks whether the stage can count down the window of the previous stage.
+    ///
+    /// The countdown lives in ISR between pattern evaluations, so it can't be
+    /// combined with an occurrence counter or a stalling external input wait.
+    fn accepts_window(&self) -> bool {
+        self.has_pattern() && self.count <= 1 && self.external.is_none()
+    }
+
+    /// Returns the number of instructions the stage takes.
+    ///
+    /// # Arguments
+    ///
+    /// * `window` - Sample clocks the stage has to match within, zero waits forever.
+    fn len(&self, window: u32) -> usize {
+        // A condition filling PIO memory by itself can't fit, don't repeat it
+        // for counts and holds beyond the scratch buffer.
+        let mut asm = pio::Assembler::<128>::new();
+        let mut mismatch = asm.label();
+        emit_condition(&mut asm, self, &mut mismatch);
+        asm.bind(&mut mismatch);
+        if asm.instructions.len() > PIO_LEN {
+            return asm.instructions.len();
+        }
+
+        // Assemble into a roomy scratch buffer, the result may not fit PIO memory.
+        let mut asm = pio::Assembler::<{ 6 * PIO_LEN }>::new();
+        let mut restart = asm.label();
+        asm.bind(&mut restart);
+        emit_stage(&mut asm, self, window, &mut restart);
+        asm.instructions.len()
+    }
+}
+
+/// Struct representing a comparison of a group of channels against a value range.
+#[derive(Clone, Copy)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct BusRange {
+    /// First channel of the bus, carrying the least significant bit.
+    channel: u8,
+    /// Number of channels in the bus (1-16).
+    width: u8,
+    /// Lowest value in the range.
+    min: u16,
+    /// Highest value in the range.
+    max: u16,
+    /// Match values outside of the range instead.
+    outside: bool,
+}
+
+impl BusRange {
+    /// Returns the number of instructions the range comparison takes.
+    fn len(&self) -> usize {
+        // Assemble into a roomy scratch buffer, the result may not fit PIO memory.
+        let mut asm = pio::Assembler::<128>::new();
+        let mut mismatch = asm.label();
+        emit_range(&mut asm, self, &mut mismatch);
+        asm.bind(&mut mismatch);
+        asm.instructions.len()
+    }
+}
+
+/// Struct representing an external trigger input on a GPIO outside the sampled range.
+#[derive(Clone, Copy)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct ExternalTrigger {
+    /// GPIO number of the input pin.
+    pin: u8,
+    /// Condition is met while the pin is low, or on its falling edge.
+    active_low: bool,
+    /// Wait for a transition into the active level instead of the level itself.
+    edge: bool,
+}
+
+impl ExternalTrigger {
+    /// Returns the active level of the input pin.
+    fn level(&self) -> u8 {
+        !self.active_low as u8
+    }
+}
+
+/// Struct representing the trigger output pin driven when the last stage matches.
+#[derive(Clone, Copy)]
+pub struct TriggerOutput {
+    /// GPIO number of the output pin.
+    pin: u8,
+    /// Drive the pin low instead of high on trigger.
+    active_low: bool,
+    /// Pulse the pin for one sample period instead of holding it until the capture ends.
+    pulse: bool,
+}
+
+impl TriggerOutput {
+    /// Returns the GPIO number of the output pin.
+    pub fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    /// Returns the pin level while the trigger hasn't matched.
+    pub fn idle_state(&self) -> hal::pio::PinState {
+        if self.active_low {
+            hal::pio::PinState::High
+        } else {
+            hal::pio::PinState::Low
+        }
+    }
 }
 
 /// Struct representing the trigger configuration with multiple stages.
@@ -22,6 +191,18 @@ pub struct TriggerStage {
 pub struct Trigger {
     /// Array of trigger stages.
     stages: [TriggerStage; 4],
+    /// Optional output pin signalling the trigger match.
+    output: Option<TriggerOutput>,
+    /// Optional protocol decoder releasing the capture.
+    protocol: Option<ProtocolTrigger>,
+    /// Bit rate of the protocol decoder, zero keeps the decoder default.
+    protocol_rate: u32,
+    /// Value the protocol decoder matches.
+    protocol_value: u32,
+    /// Bitmask of the bits the protocol decoder compares.
+    protocol_mask: u32,
+    /// Whether the trigger waits for the next counts instead of parking after a capture.
+    rearm: bool,
 }
 
 impl Trigger {
@@ -55,84 +236,1801 @@ impl Trigger {
         self.stages[stage].delay = delay;
     }
 
+    /// Sets the occurrence count for a specific trigger stage.
+    ///
+    /// The stage advances on the `count`-th match of its pattern; the pattern
+    /// must deassert between occurrences. Zero and one both mean the first match.
+    ///
+    /// # Arguments
+    ///
+    /// * `stage` - Index of the trigger stage (0-3).
+    /// * `count` - Number of occurrences to wait for.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the trigger programs wouldn't fit PIO memory and the count was left unchanged.
+    pub fn set_count(&mut self, stage: usize, count: u32) -> bool {
+        self.update(|trigger| trigger.stages[stage].count = count)
+    }
+
+    /// Sets the window the stage following a specific trigger stage has to match within.
+    ///
+    /// If the next stage doesn't match within `window` sample clocks, the
+    /// sequence restarts from the first stage. The window is ignored when the
+    /// next stage has an occurrence count or an external input condition.
+    ///
+    /// # Arguments
+    ///
+    /// * `stage` - Index of the trigger stage (0-3).
+    /// * `window` - Window in sample clocks, zero to wait forever.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the trigger programs wouldn't fit PIO memory and the window was left unchanged.
+    pub fn set_window(&mut self, stage: usize, window: u32) -> bool {
+        self.update(|trigger| trigger.stages[stage].window = window)
+    }
+
+    /// Sets the duration the pattern of a specific trigger stage has to persist.
+    ///
+    /// The stage advances once its pattern has matched continuously for more
+    /// than `hold` sample clocks. The hold is ignored for stages with an
+    /// occurrence count or without a pattern.
+    ///
+    /// # Arguments
+    ///
+    /// * `stage` - Index of the trigger stage (0-3).
+    /// * `hold` - Duration in sample clocks, zero to advance on the first match.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the trigger programs wouldn't fit PIO memory and the hold was left unchanged.
+    pub fn set_hold(&mut self, stage: usize, hold: u32) -> bool {
+        self.update(|trigger| trigger.stages[stage].hold = hold)
+    }
+
+    /// Sets the bus value range condition for a specific trigger stage.
+    ///
+    /// The bus is sampled separately after the stage pattern, both must hold.
+    /// Bounds are inclusive and swapped if out of order. The comparison is an
+    /// MSB-first decision tree taking up to two instructions per bit past the
+    /// common prefix of the bounds, ranges taking more than `RANGE_BUDGET`
+    /// instructions are rejected.
+    ///
+    /// # Arguments
+    ///
+    /// * `stage` - Index of the trigger stage (0-3).
+    /// * `range` - First channel, bus width, bounds and outside flag, or `None` to disable the range.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the bus runs past the probes or the range doesn't fit the
+    /// instruction budget, the range is left unchanged then.
+    pub fn set_range(&mut self, stage: usize, range: Option<(u8, u8, u16, u16, bool)>) -> bool {
+        if range.is_some_and(|(channel, width, ..)| {
+            width == 0 || channel as usize + width as usize > PROBES
+        }) {
+            // The sampling shift would underflow past the last probe.
+            return false;
+        }
+        let range = range.map(|(channel, width, min, max, outside)| {
+            let limit = ((1u32 << width) - 1) as u16;
+            BusRange {
+                channel,
+                width,
+                min: min.min(max).min(limit),
+                max: max.max(min).min(limit),
+                outside,
+            }
+        });
+        if range.is_some_and(|range| range.len() > RANGE_BUDGET) {
+            return false;
+        }
+        self.stages[stage].range = range;
+        true
+    }
+
+    /// Sets the external trigger input condition for a specific trigger stage.
+    ///
+    /// The condition is evaluated before the stage pattern, so both must hold.
+    /// A stage with an empty mask waits for the external input only.
+    ///
+    /// # Arguments
+    ///
+    /// * `stage` - Index of the trigger stage (0-3).
+    /// * `external` - GPIO number, polarity and edge mode, or `None` to disable the input.
+    pub fn set_external(&mut self, stage: usize, external: Option<(u8, bool, bool)>) {
+        self.stages[stage].external = external.map(|(pin, active_low, edge)| ExternalTrigger {
+            pin,
+            active_low,
+            edge,
+        });
+    }
+
+    /// Assigns a specific trigger stage to an OR group.
+    ///
+    /// Stages within a group are matched in sequence, the trigger fires as soon
+    /// as any group completes. A single group runs on the trigger state machine
+    /// itself, multiple groups run on helper state machines in parallel.
+    ///
+    /// # Arguments
+    ///
+    /// * `stage` - Index of the trigger stage (0-3).
+    /// * `group` - Index of the OR group (0-1).
+    pub fn set_group(&mut self, stage: usize, group: u8) {
+        self.stages[stage].group = group;
+    }
+
+    /// Sets the protocol decoder releasing the capture.
+    ///
+    /// The decoder is ORed with the trigger stages and occupies the last helper
+    /// state machine, so OR group 1 is unavailable while it's set.
+    ///
+    /// The rate, value and mask set so far carry over to the new decoder.
+    ///
+    /// # Arguments
+    ///
+    /// * `protocol` - Protocol decoder, or `None` to disable it.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the trigger programs wouldn't fit PIO memory and the decoder was left unchanged.
+    pub fn set_protocol(&mut self, protocol: Option<ProtocolTrigger>) -> bool {
+        let protocol = protocol.map(|mut protocol| {
+            if self.protocol_rate != 0 {
+                protocol.set_rate(self.protocol_rate);
+            }
+            protocol.set_value(self.protocol_value);
+            protocol.set_mask(self.protocol_mask);
+            protocol
+        });
+        self.update(|trigger| trigger.protocol = protocol)
+    }
+
+    /// Sets the bit rate of the protocol decoder.
+    ///
+    /// Kept for decoders selected later, synchronous protocols ignore it.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - Bit rate in bits per second.
+    pub fn set_protocol_rate(&mut self, rate: u32) {
+        self.protocol_rate = rate;
+        if let Some(protocol) = &mut self.protocol {
+            protocol.set_rate(rate);
+        }
+    }
+
+    /// Sets the value the protocol decoder matches.
+    ///
+    /// Kept for decoders selected later, the value doesn't change the program length.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Protocol specific value to match.
+    pub fn set_protocol_value(&mut self, value: u32) {
+        self.protocol_value = value;
+        if let Some(protocol) = &mut self.protocol {
+            protocol.set_value(value);
+        }
+    }
+
+    /// Sets the mask of the bits the protocol decoder compares.
+    ///
+    /// Kept for decoders selected later, only SPI words support masking.
+    ///
+    /// # Arguments
+    ///
+    /// * `mask` - Bitmask of the compared bits.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the trigger programs wouldn't fit PIO memory and the mask was left unchanged.
+    pub fn set_protocol_mask(&mut self, mask: u32) -> bool {
+        self.update(|trigger| {
+            trigger.protocol_mask = mask;
+            if let Some(protocol) = &mut trigger.protocol {
+                protocol.set_mask(mask);
+            }
+        })
+    }
+
+    /// Returns the trigger stages.
+    #[cfg(test)]
+    pub fn stages(&self) -> &[TriggerStage; 4] {
+        &self.stages
+    }
+
+    /// Checks whether the trigger is evaluated by helper state machines.
+    pub fn has_helpers(&self) -> bool {
+        self.protocol.is_some() || self.has_groups()
+    }
+
+    /// Checks whether active stages are split across several OR groups.
+    pub fn has_groups(&self) -> bool {
+        let mut groups = self
+            .stages
+            .iter()
+            .filter(|s| s.is_active())
+            .map(|s| s.group);
+        match groups.next() {
+            Some(first) => groups.any(|group| group != first),
+            None => false,
+        }
+    }
+
+    /// Configures the trigger output pin.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - GPIO number, polarity and pulse mode, or `None` to disable the output.
+    pub fn set_output(&mut self, output: Option<(u8, bool, bool)>) {
+        self.output = output.map(|(pin, active_low, pulse)| TriggerOutput {
+            pin,
+            active_low,
+            pulse,
+        });
+    }
+
+    /// Selects whether the trigger re-arms after the post-trigger count.
+    ///
+    /// # Arguments
+    ///
+    /// * `rearm` - Whether to wait for the next pre- and post-trigger counts.
+    pub fn set_rearm(&mut self, rearm: bool) {
+        self.rearm = rearm;
+    }
+
+    /// Returns the trigger output pin configuration.
+    pub fn output(&self) -> Option<TriggerOutput> {
+        self.output
+    }
+
+    /// Returns the number of instructions the trigger programs take together.
+    ///
+    /// Counts the program of the trigger state machine and the helper
+    /// programs without assembling stages that may not fit PIO memory.
+    pub fn len(&self) -> usize {
+        let mut len = self.assemble(false).code.len();
+        if !self.has_helpers() {
+            return len.saturating_add(stages_len(self.stages.iter().filter(|s| s.is_active())));
+        }
+        // Mirror `helpers`, the protocol decoder takes the place of the last group.
+        let groups = match self.protocol {
+            Some(protocol) => {
+                len += protocol.len();
+                TRIGGER_GROUPS - 1
+            }
+            None => TRIGGER_GROUPS,
+        };
+        for group in 0..groups as u8 {
+            let mut stages = self
+                .stages
+                .iter()
+                .filter(|s| s.is_active() && s.group == group)
+                .peekable();
+            if stages.peek().is_some() {
+                // The group waits for the pre-trigger samples, then raises the flag once its stages match.
+                len = len.saturating_add(stages_len(stages) + 2);
+            }
+        }
+        len
+    }
+
+    /// Checks whether the trigger programs fit PIO memory next to the capture program.
+    pub fn fits(&self) -> bool {
+        let capture = capture_program(PROBES as _).code.len();
+        self.len().saturating_add(capture) <= PIO_LEN
+    }
+
+    /// Applies a change, reverting it if it grows the trigger programs past PIO memory.
+    ///
+    /// Changes that don't grow the programs are kept, so an oversized
+    /// configuration can still be trimmed one setting at a time.
+    ///
+    /// # Arguments
+    ///
+    /// * `change` - Change to apply.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the change was reverted.
+    fn update(&mut self, change: impl FnOnce(&mut Self)) -> bool {
+        let previous = *self;
+        change(self);
+        if !self.fits() && self.len() > previous.len() {
+            *self = previous;
+            return false;
+        }
+        true
+    }
+
     /// Compiles the trigger configuration into a PIO program.
     ///
-    /// This method assembles the trigger logic based on the configured stages.
+    /// The program runs next to the free-running capture state machine and
+    /// expects two words in its TX FIFO: the pre- and post-trigger sample
+    /// counts minus one. It lets the pre-trigger samples pass before arming
+    /// the stages, so the ring buffer holds them once the trigger fires. After
+    /// the match it lets the post-trigger samples pass and pushes a word into
+    /// the RX FIFO, which paces the DMA channel stopping the ring.
     ///
     /// # Returns
     ///
-    /// A compiled `TriggerProgram` ready to be installed into PIO.
+    /// A compiled `TriggerProgram` ready to be installed into PIO. The wrap
+    /// target is the first instruction after the trigger fired.
     pub fn compile(&self) -> TriggerProgram {
+        self.assemble(true)
+    }
+
+    /// Assembles the program of the trigger state machine.
+    ///
+    /// # Arguments
+    ///
+    /// * `stages` - Whether to emit the stages, left out to size the rest of the program.
+    ///
+    /// # Returns
+    ///
+    /// The assembled `TriggerProgram`.
+    fn assemble(&self, stages: bool) -> TriggerProgram {
+        let mut asm = match self.output {
+            Some(_) => TriggerAssembler::new_with_side_set(pio::SideSet::new(true, 1, false)),
+            None => TriggerAssembler::new(),
+        };
+        let mut wrap_target = asm.label();
+        let mut wrap_source = asm.label();
+        let mut pretrigger = asm.label();
+        let mut posttrigger = asm.label();
+        let mut done = asm.label();
+        let mut start = asm.label();
+
+        // Let the pre-trigger samples fill the ring before looking for a match.
+        asm.bind(&mut start);
+        match self.output {
+            // A re-armed output returns to its idle level.
+            Some(out) if self.rearm => asm.pull_with_side_set(false, true, out.active_low as u8),
+            _ => asm.pull(false, true),
+        }
+        asm.mov(
+            pio::MovDestination::X,
+            pio::MovOperation::None,
+            pio::MovSource::OSR,
+        );
+        asm.bind(&mut pretrigger);
+        asm.jmp(pio::JmpCondition::XDecNonZero, &mut pretrigger);
+
+        if self.has_helpers() {
+            // Release the helper state machines and wait for the first one to match.
+            asm.irq(true, false, PRETRIGGER_IRQ, false);
+            asm.wait(1, pio::WaitSource::IRQ, TRIGGER_IRQ, false);
+        } else if stages {
+            emit_stages(&mut asm, self.stages.iter().filter(|s| s.is_active()));
+        }
+
+        // Forcing the trigger restarts the state machine here.
+        asm.bind(&mut wrap_target);
+        match self.output {
+            Some(TriggerOutput {
+                active_low, pulse, ..
+            }) => {
+                let active = !active_low as u8;
+                asm.pull_with_side_set(false, true, active);
+                // Assert the output for the first sample only.
+                let level = if pulse { active ^ 1 } else { active };
+                asm.mov_with_side_set(
+                    pio::MovDestination::X,
+                    pio::MovOperation::None,
+                    pio::MovSource::OSR,
+                    level,
+                );
+            }
+            None => {
+                asm.pull(false, true);
+                asm.mov(
+                    pio::MovDestination::X,
+                    pio::MovOperation::None,
+                    pio::MovSource::OSR,
+                );
+            }
+        }
+        asm.bind(&mut posttrigger);
+        asm.jmp(pio::JmpCondition::XDecNonZero, &mut posttrigger);
+
+        // Stop the ring and park, the output keeps its level. Re-arming
+        // waits for the next counts once the ring moves on instead.
+        asm.push(false, true);
+        asm.bind(&mut done);
+        match self.rearm {
+            true => asm.jmp(pio::JmpCondition::Always, &mut start),
+            false => asm.jmp(pio::JmpCondition::Always, &mut done),
+        }
+        asm.bind(&mut wrap_source);
+
+        asm.assemble_with_wrap(wrap_source, wrap_target)
+    }
+
+    /// Compiles the stages of an OR group into a helper PIO program.
+    ///
+    /// The program starts once `PRETRIGGER_IRQ` is cleared and raises
+    /// `TRIGGER_IRQ` once all stages of the group have matched.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - Index of the OR group (0-1).
+    ///
+    /// # Returns
+    ///
+    /// A compiled `TriggerProgram`, or `None` if the group is empty or the
+    /// trigger state machine evaluates the stages itself.
+    pub fn compile_group(&self, group: u8) -> Option<TriggerProgram> {
+        let mut stages = self
+            .stages
+            .iter()
+            .filter(|s| s.is_active() && s.group == group)
+            .peekable();
+        if !self.has_helpers() || stages.peek().is_none() {
+            return None;
+        }
+
         let mut asm = TriggerAssembler::new();
         let mut wrap_target = asm.label();
         let mut wrap_source = asm.label();
+        // Matches within the pre-trigger samples don't count.
+        asm.wait(0, pio::WaitSource::IRQ, PRETRIGGER_IRQ, false);
+        emit_stages(&mut asm, stages);
 
-        // Iterate over each trigger stage that has a non-zero mask.
-        for TriggerStage {
-            mut mask,
-            mut pattern,
-            delay: _,
-        } in self.stages.iter().filter(|s| s.mask != 0)
-        {
-            let mut stage_label = asm.label();
-            asm.bind(&mut stage_label);
-
-            // Move bits from PINS to OSR with bit reversal.
-            asm.mov(
-                pio::MovDestination::OSR,
-                pio::MovOperation::BitReverse,
-                pio::MovSource::PINS,
-            );
-
-            loop {
-                match mask.trailing_zeros() {
-                    0 => {}
-                    32 => break, // Exit loop if no more bits are set.
-                    zeros => {
-                        // Skip the trailing zeros by outputting NULL bits.
-                        asm.out(pio::OutDestination::NULL, zeros as _);
-                        // Shift the pattern and mask to process the next set of bits.
-                        pattern >>= zeros;
-                        mask >>= zeros;
-                    }
+        // Keep raising the flag, the trigger state machine consumes it once.
+        asm.bind(&mut wrap_target);
+        asm.irq(false, false, TRIGGER_IRQ, false);
+        asm.bind(&mut wrap_source);
+        Some(asm.assemble_with_wrap(wrap_source, wrap_target))
+    }
+
+    /// Compiles the programs for the helper state machines.
+    ///
+    /// # Returns
+    ///
+    /// One optional `HelperProgram` per helper state machine.
+    pub fn helpers(&self) -> [Option<HelperProgram>; TRIGGER_GROUPS] {
+        let mut helpers = [0, 1].map(|group| {
+            self.compile_group(group).map(|program| HelperProgram {
+                program,
+                divisor: None,
+                in_pin_base: PIN_BASE as _,
+                in_shift: ShiftDirection::Left,
+                jmp_pin: 0,
+                preload: None,
+            })
+        });
+        if let Some(protocol) = self.protocol {
+            helpers[TRIGGER_GROUPS - 1] = Some(protocol.compile());
+        }
+        helpers
+    }
+}
+
+/// Assembles the program of the capture state machine.
+///
+/// The capture state machine samples the probes on every clock, autopush
+/// hands `32 / width` samples at a time to the ring DMA, the oldest one in
+/// the lowest bits.
+///
+/// # Arguments
+///
+/// * `width` - Number of bits sampled per clock: 16, 8, 4, 2 or 1.
+///
+/// # Returns
+///
+/// A compiled `TriggerProgram` ready to be installed into PIO.
+pub fn capture_program(width: u8) -> TriggerProgram {
+    let mut asm = TriggerAssembler::new();
+    let mut wrap_target = asm.label();
+    let mut wrap_source = asm.label();
+    asm.bind(&mut wrap_target);
+    asm.r#in(pio::InSource::PINS, width);
+    asm.bind(&mut wrap_source);
+    asm.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Assembles the program of the transition detector state machine.
+///
+/// The detector samples the probes every `TRANSITION_TICK` clocks and
+/// pushes a record only when their value changes, or when its 16-bit
+/// counter runs out. A record holds the new value in the upper half and the
+/// counter in the lower half, see `transition_ticks`. Expects the input
+/// shift direction to be left.
+///
+/// # Returns
+///
+/// A compiled `TriggerProgram` ready to be installed into PIO.
+pub fn transition_program() -> TriggerProgram {
+    let mut asm = TriggerAssembler::new();
+    let mut wrap_target = asm.label();
+    let mut wrap_source = asm.label();
+    let mut change = asm.label();
+    let mut record = asm.label();
+
+    // No probe value matches, the first sample is always recorded.
+    asm.mov(
+        pio::MovDestination::X,
+        pio::MovOperation::Invert,
+        pio::MovSource::NULL,
+    );
+
+    // X holds the last recorded value, Y counts down the ticks since then.
+    asm.bind(&mut wrap_target);
+    asm.mov(
+        pio::MovDestination::OSR,
+        pio::MovOperation::None,
+        pio::MovSource::Y,
+    );
+    asm.mov(
+        pio::MovDestination::ISR,
+        pio::MovOperation::None,
+        pio::MovSource::NULL,
+    );
+    asm.r#in(pio::InSource::PINS, PROBES as _);
+    asm.mov(
+        pio::MovDestination::Y,
+        pio::MovOperation::None,
+        pio::MovSource::ISR,
+    );
+    asm.jmp(pio::JmpCondition::XNotEqualY, &mut change);
+    asm.mov(
+        pio::MovDestination::Y,
+        pio::MovOperation::None,
+        pio::MovSource::OSR,
+    );
+    asm.jmp(pio::JmpCondition::YDecNonZero, &mut wrap_target);
+
+    // The counter ran out, record the unchanged value.
+    asm.mov(
+        pio::MovDestination::Y,
+        pio::MovOperation::None,
+        pio::MovSource::X,
+    );
+    asm.bind(&mut record);
+    asm.r#in(pio::InSource::OSR, 16);
+    asm.push(false, true);
+    asm.mov(
+        pio::MovDestination::X,
+        pio::MovOperation::None,
+        pio::MovSource::Y,
+    );
+    // Reload the counter with 0xffff.
+    asm.mov(
+        pio::MovDestination::Y,
+        pio::MovOperation::Invert,
+        pio::MovSource::NULL,
+    );
+    asm.r#in(pio::InSource::Y, 16);
+    asm.mov(
+        pio::MovDestination::Y,
+        pio::MovOperation::None,
+        pio::MovSource::ISR,
+    );
+    asm.bind(&mut wrap_source);
+
+    // Pad changes to two ticks like the counter running out.
+    asm.bind(&mut change);
+    asm.jmp_with_delay(pio::JmpCondition::Always, &mut record, 2);
+
+    asm.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Returns the number of ticks between a transition record and the previous one.
+///
+/// Recording takes two ticks, every tick without a change after that
+/// decrements the counter from `0xffff`.
+///
+/// # Arguments
+///
+/// * `record` - Record pushed by the transition detector.
+///
+/// # Returns
+///
+/// Ticks of `TRANSITION_TICK` clocks since the previous record.
+pub fn transition_ticks(record: u32) -> u32 {
+    0x10001 - (record & 0xffff)
+}
+
+/// Emits code waiting for a sequence of trigger stages to match.
+///
+/// # Arguments
+///
+/// * `asm` - Assembler to emit instructions into.
+/// * `stages` - Trigger stages to wait for, in order.
+fn emit_stages<'a, const N: usize>(
+    asm: &mut pio::Assembler<N>,
+    stages: impl Iterator<Item = &'a TriggerStage>,
+) {
+    let mut restart = asm.label();
+    asm.bind(&mut restart);
+
+    let mut window = 0;
+    for stage in stages {
+        let window = core::mem::replace(&mut window, stage.window);
+        let window = if stage.accepts_window() { window } else { 0 };
+        emit_stage(asm, stage, window, &mut restart);
+    }
+}
+
+/// Returns the number of instructions `emit_stages` takes for a sequence of stages.
+///
+/// # Arguments
+///
+/// * `stages` - Trigger stages to wait for, in order.
+fn stages_len<'a>(stages: impl Iterator<Item = &'a TriggerStage>) -> usize {
+    let mut window = 0;
+    stages
+        .map(|stage| {
+            let window = core::mem::replace(&mut window, stage.window);
+            stage.len(if stage.accepts_window() { window } else { 0 })
+        })
+        .fold(0, usize::saturating_add)
+}
+
+/// Emits code waiting for a single trigger stage to match.
+///
+/// # Arguments
+///
+/// * `asm` - Assembler to emit instructions into.
+/// * `stage` - Trigger stage to wait for.
+/// * `window` - Sample clocks the stage has to match within, zero waits forever.
+/// * `restart` - Label of the first stage, taken when the window expires.
+fn emit_stage<const N: usize>(
+    asm: &mut pio::Assembler<N>,
+    stage: &TriggerStage,
+    window: u32,
+    restart: &mut pio::Label,
+) {
+    let TriggerStage {
+        count,
+        external,
+        hold,
+        ..
+    } = *stage;
+    let hold = if stage.has_pattern() && count <= 1 {
+        hold
+    } else {
+        0
+    };
+
+    // Occurrence counter lives in ISR, the match code owns X and Y.
+    if count > 1 {
+        load_counter(asm, count - 1);
+    }
+
+    // Window countdown lives in ISR as well, in pattern evaluations. Early
+    // mismatches make an evaluation shorter than a full match, size the
+    // countdown for the shortest one so the window is never cut short.
+    if window > 0 {
+        let period = 3 + condition_cycles(stage).0;
+        load_counter(asm, window.div_ceil(period));
+    }
+
+    let mut stage_label = asm.label();
+    asm.bind(&mut stage_label);
+
+    if window > 0 {
+        let mut tick_label = asm.label();
+        asm.mov(
+            pio::MovDestination::Y,
+            pio::MovOperation::BitReverse,
+            pio::MovSource::ISR,
+        );
+        asm.jmp(pio::JmpCondition::YDecNonZero, &mut tick_label);
+        asm.jmp(pio::JmpCondition::Always, restart);
+        asm.bind(&mut tick_label);
+        asm.mov(
+            pio::MovDestination::ISR,
+            pio::MovOperation::BitReverse,
+            pio::MovSource::Y,
+        );
+    }
+
+    // Wait for the external input before looking at the sampled pins.
+    if let Some(ext) = external {
+        if ext.edge {
+            asm.wait(ext.level() ^ 1, pio::WaitSource::GPIO, ext.pin, false);
+        }
+        asm.wait(ext.level(), pio::WaitSource::GPIO, ext.pin, false);
+    }
+
+    emit_condition(asm, stage, &mut stage_label);
+
+    if count > 1 {
+        let mut next_label = asm.label();
+        let mut rearm_label = asm.label();
+
+        // Advance once the counter is exhausted, otherwise decrement it.
+        asm.mov(
+            pio::MovDestination::Y,
+            pio::MovOperation::BitReverse,
+            pio::MovSource::ISR,
+        );
+        asm.jmp(pio::JmpCondition::YDecNonZero, &mut rearm_label);
+        asm.jmp(pio::JmpCondition::Always, &mut next_label);
+        asm.bind(&mut rearm_label);
+        asm.mov(
+            pio::MovDestination::ISR,
+            pio::MovOperation::BitReverse,
+            pio::MovSource::Y,
+        );
+
+        if stage.has_pattern() {
+            // Wait for the pattern to deassert before looking for the next occurrence.
+            let mut release_label = asm.label();
+            asm.bind(&mut release_label);
+            emit_condition(asm, stage, &mut stage_label);
+            asm.jmp(pio::JmpCondition::Always, &mut release_label);
+        } else {
+            // External input only, edges release by themselves, levels must drop first.
+            if let Some(ext) = external.filter(|ext| !ext.edge) {
+                asm.wait(ext.level() ^ 1, pio::WaitSource::GPIO, ext.pin, false);
+            }
+            asm.jmp(pio::JmpCondition::Always, &mut stage_label);
+        }
+        asm.bind(&mut next_label);
+    }
+
+    if hold > 0 {
+        let mut next_label = asm.label();
+        let mut hold_label = asm.label();
+        let mut tick_label = asm.label();
+
+        // Keep matching the pattern until the countdown in ISR runs out. The
+        // window countdown is lost once ISR is reused, a drop restarts the sequence.
+        let period = 4 + condition_cycles(stage).1;
+        load_counter(asm, hold / period + 1);
+        asm.bind(&mut hold_label);
+        asm.mov(
+            pio::MovDestination::Y,
+            pio::MovOperation::BitReverse,
+            pio::MovSource::ISR,
+        );
+        asm.jmp(pio::JmpCondition::YDecNonZero, &mut tick_label);
+        asm.jmp(pio::JmpCondition::Always, &mut next_label);
+        asm.bind(&mut tick_label);
+        asm.mov(
+            pio::MovDestination::ISR,
+            pio::MovOperation::BitReverse,
+            pio::MovSource::Y,
+        );
+        emit_condition(
+            asm,
+            stage,
+            if window > 0 {
+                restart
+            } else {
+                &mut stage_label
+            },
+        );
+        asm.jmp(pio::JmpCondition::Always, &mut hold_label);
+        asm.bind(&mut next_label);
+    }
+}
+
+/// Emits code sampling the pins and checking the pattern and range of a stage.
+///
+/// Falls through on match, jumps to `mismatch` otherwise. Clobbers X, Y and OSR.
+///
+/// # Arguments
+///
+/// * `asm` - Assembler to emit instructions into.
+/// * `stage` - Trigger stage to check.
+/// * `mismatch` - Label to jump to when the sample doesn't match.
+fn emit_condition<const N: usize>(
+    asm: &mut pio::Assembler<N>,
+    stage: &TriggerStage,
+    mismatch: &mut pio::Label,
+) {
+    if stage.mask != 0 {
+        // Move bits from PINS to OSR with bit reversal.
+        asm.mov(
+            pio::MovDestination::OSR,
+            pio::MovOperation::BitReverse,
+            pio::MovSource::PINS,
+        );
+        emit_match(asm, stage.mask, stage.pattern, mismatch);
+    }
+    if let Some(range) = stage.range {
+        emit_range(asm, &range, mismatch);
+    }
+}
+
+/// Returns the number of cycles `emit_condition` takes on its shortest paths.
+///
+/// # Arguments
+///
+/// * `stage` - Trigger stage to check.
+///
+/// # Returns
+///
+/// Cycles to reject a sample and cycles to accept a matching sample.
+fn condition_cycles(stage: &TriggerStage) -> (u32, u32) {
+    // Address 0 stands in for the mismatch label, the condition starts at 1.
+    let mut asm = TriggerAssembler::new();
+    let mut mismatch = asm.label();
+    asm.bind(&mut mismatch);
+    asm.mov(
+        pio::MovDestination::Y,
+        pio::MovOperation::None,
+        pio::MovSource::Y,
+    );
+    emit_condition(&mut asm, stage, &mut mismatch);
+
+    // The condition only jumps forward, walk it backwards keeping the shortest
+    // distances to the mismatch label and past the last instruction.
+    let len = asm.instructions.len();
+    let mut dist = [(u32::MAX, u32::MAX); 33];
+    dist[len] = (u32::MAX, 0);
+    for addr in (1..len).rev() {
+        let next = dist[addr + 1];
+        let (reject, accept) = match asm.instructions[addr].operands {
+            pio::InstructionOperands::JMP { condition, address } => {
+                let target = match address {
+                    0 => (0, u32::MAX),
+                    address => dist[address as usize],
                 };
-                match mask.trailing_ones() {
-                    0 => {}
-                    1 => {
-                        // If the next bit is a single one, handle it as a condition.
-                        let cond = if pattern & 1 == 1 {
-                            pio::JmpCondition::XIsZero
-                        } else {
-                            pio::JmpCondition::XDecNonZero
-                        };
-                        asm.out(pio::OutDestination::X, 1);
-                        asm.jmp(cond, &mut stage_label);
-                        // Shift the pattern and mask after processing.
-                        pattern >>= 1;
-                        mask >>= 1;
-                    }
-                    ones => {
-                        // Handle multiple consecutive ones, up to 5 bits.
-                        let bits = ones.min(5);
-                        let val = pattern & ((1 << bits) - 1);
-                        asm.set(pio::SetDestination::Y, val as _);
-                        asm.out(pio::OutDestination::X, bits as _);
-                        asm.jmp(pio::JmpCondition::XNotEqualY, &mut stage_label);
-                        // Shift the pattern and mask after processing.
-                        pattern >>= bits;
-                        mask >>= bits;
-                    }
+                match condition {
+                    pio::JmpCondition::Always => target,
+                    _ => (target.0.min(next.0), target.1.min(next.1)),
+                }
+            }
+            _ => next,
+        };
+        dist[addr] = (reject.saturating_add(1), accept.saturating_add(1));
+    }
+    dist[1]
+}
+
+/// Emits code sampling a bus and checking whether its value lies inside or outside a range.
+///
+/// Falls through on match, jumps to `mismatch` otherwise. Clobbers X, Y and OSR.
+///
+/// # Arguments
+///
+/// * `asm` - Assembler to emit instructions into.
+/// * `range` - Bus range to check.
+/// * `mismatch` - Label to jump to when the sample doesn't match.
+fn emit_range<const N: usize>(
+    asm: &mut pio::Assembler<N>,
+    range: &BusRange,
+    mismatch: &mut pio::Label,
+) {
+    // Sample the bus with its most significant channel at the top of OSR.
+    asm.mov(
+        pio::MovDestination::OSR,
+        pio::MovOperation::None,
+        pio::MovSource::PINS,
+    );
+    asm.out(
+        pio::OutDestination::NULL,
+        32 - PIN_BASE as u8 - range.channel - range.width,
+    );
+
+    let mut pass = asm.label();
+    if range.outside {
+        emit_range_tree(asm, range, mismatch, &mut pass);
+        asm.jmp(pio::JmpCondition::Always, mismatch);
+    } else {
+        emit_range_tree(asm, range, &mut pass, mismatch);
+    }
+    asm.bind(&mut pass);
+}
+
+/// Emits a decision tree comparing the bus in OSR against the range bounds MSB first.
+///
+/// While the value follows the common prefix of both bounds it can still
+/// leave the range. Once it departs from one bound only the other bound is
+/// left to compare against, one jump per run of equal bits in that bound.
+///
+/// Falls through if the value lies inside the range. Clobbers X and Y.
+///
+/// # Arguments
+///
+/// * `asm` - Assembler to emit instructions into.
+/// * `range` - Bus range to check.
+/// * `inside` - Label to jump to when the value is known to lie inside the range.
+/// * `outside` - Label to jump to when the value is known to lie outside the range.
+fn emit_range_tree<const N: usize>(
+    asm: &mut pio::Assembler<N>,
+    range: &BusRange,
+    inside: &mut pio::Label,
+    outside: &mut pio::Label,
+) {
+    let width = range.width as u32;
+    let (min, max) = (range.min as u32, range.max as u32);
+
+    // Follow the common prefix of the bounds.
+    let prefix = ((min ^ max) << (32 - width)).leading_zeros().min(width);
+    let mut bits = prefix;
+    while bits > 0 {
+        let run = bits.min(5);
+        bits -= run;
+        let value = min >> (width - prefix + bits) & ((1 << run) - 1);
+        emit_run(asm, run, value, outside);
+    }
+    if prefix == width {
+        return;
+    }
+
+    // Bounds diverge here, min has a zero and max a one.
+    let mut low = asm.label();
+    let rest = width - prefix - 1;
+    let tail = (1 << rest) - 1;
+    asm.out(pio::OutDestination::X, 1);
+    asm.jmp(pio::JmpCondition::XIsZero, &mut low);
+    // Above min already, compare against max.
+    emit_bound(asm, max & tail, rest, true, inside, outside);
+    asm.jmp(pio::JmpCondition::Always, inside);
+    // Below max already, compare against min.
+    asm.bind(&mut low);
+    emit_bound(asm, min & tail, rest, false, outside, inside);
+}
+
+/// Emits code comparing the next `bits` bits of OSR against a single bound.
+///
+/// Jumps to `less` or `greater` as soon as the value departs from the bound,
+/// falls through if it equals the bound or can't depart any more. Clobbers X and Y.
+///
+/// # Arguments
+///
+/// * `asm` - Assembler to emit instructions into.
+/// * `bound` - Value of the bound.
+/// * `bits` - Number of bits to compare.
+/// * `upper` - The bound is a maximum, trailing ones in it can't be exceeded.
+/// * `less` - Label to jump to when the value is below the bound.
+/// * `greater` - Label to jump to when the value is above the bound.
+fn emit_bound<const N: usize>(
+    asm: &mut pio::Assembler<N>,
+    bound: u32,
+    bits: u32,
+    upper: bool,
+    less: &mut pio::Label,
+    greater: &mut pio::Label,
+) {
+    // Trailing ones of a maximum or zeros of a minimum accept any value.
+    let trailing = match upper {
+        true => bound.trailing_ones(),
+        false => bound.trailing_zeros(),
+    }
+    .min(bits);
+    let mut bits = bits - trailing;
+    let mut bound = bound.checked_shr(trailing).unwrap_or(0);
+    while bits > 0 {
+        let top = bound << (32 - bits);
+        if top >> 31 == 1 {
+            // Any zero among ones of the bound puts the value below it.
+            let run = top.leading_ones().min(bits).min(5);
+            bits -= run;
+            emit_run(asm, run, (1 << run) - 1, less);
+        } else {
+            // Any one among zeros of the bound puts the value above it.
+            let run = top.leading_zeros().min(bits);
+            bits -= run;
+            emit_run(asm, run, 0, greater);
+        }
+        bound &= (1 << bits) - 1;
+    }
+}
+
+/// Emits code shifting `bits` bits out of OSR and comparing them against `value`.
+///
+/// Falls through if they are equal, jumps to `differ` otherwise. Runs of
+/// zeros may be wider than the 5 bits `set` can load. Clobbers X and Y.
+///
+/// # Arguments
+///
+/// * `asm` - Assembler to emit instructions into.
+/// * `bits` - Number of bits to compare.
+/// * `value` - Expected value of the bits.
+/// * `differ` - Label to jump to when the bits differ.
+fn emit_run<const N: usize>(
+    asm: &mut pio::Assembler<N>,
+    bits: u32,
+    value: u32,
+    differ: &mut pio::Label,
+) {
+    asm.out(pio::OutDestination::X, bits as _);
+    match (value, bits) {
+        (0, _) => asm.jmp(pio::JmpCondition::XDecNonZero, differ),
+        (_, 1) => asm.jmp(pio::JmpCondition::XIsZero, differ),
+        (value, _) => {
+            asm.set(pio::SetDestination::Y, value as _);
+            asm.jmp(pio::JmpCondition::XNotEqualY, differ);
+        }
+    }
+}
+
+/// Emits code matching the bit-reversed sample in OSR against `pattern`.
+///
+/// Falls through on match, jumps to `mismatch` otherwise. Clobbers X and Y.
+///
+/// # Arguments
+///
+/// * `asm` - Assembler to emit instructions into.
+/// * `mask` - Bitmask of the bits to compare.
+/// * `pattern` - Expected values of the masked bits.
+/// * `mismatch` - Label to jump to when the sample doesn't match.
+pub fn emit_match<const N: usize>(
+    asm: &mut pio::Assembler<N>,
+    mut mask: u32,
+    mut pattern: u32,
+    mismatch: &mut pio::Label,
+) {
+    loop {
+        match mask.trailing_zeros() {
+            0 => {}
+            32 => break, // Exit loop if no more bits are set.
+            zeros => {
+                // Skip the trailing zeros by outputting NULL bits.
+                asm.out(pio::OutDestination::NULL, zeros as _);
+                // Shift the pattern and mask to process the next set of bits.
+                pattern >>= zeros;
+                mask >>= zeros;
+            }
+        };
+        match mask.trailing_ones() {
+            0 => {}
+            1 => {
+                // If the next bit is a single one, handle it as a condition.
+                let cond = if pattern & 1 == 1 {
+                    pio::JmpCondition::XIsZero
+                } else {
+                    pio::JmpCondition::XDecNonZero
                 };
+                asm.out(pio::OutDestination::X, 1);
+                asm.jmp(cond, mismatch);
+                // Shift the pattern and mask after processing.
+                pattern >>= 1;
+                mask >>= 1;
+            }
+            ones => {
+                // Handle multiple consecutive ones, up to 5 bits.
+                let bits = ones.min(5);
+                // `out` delivers the lowest pin as the most significant bit of X.
+                let val = (pattern & ((1 << bits) - 1)).reverse_bits() >> (32 - bits);
+                asm.set(pio::SetDestination::Y, val as _);
+                asm.out(pio::OutDestination::X, bits as _);
+                asm.jmp(pio::JmpCondition::XNotEqualY, mismatch);
+                // Shift the pattern and mask after processing.
+                pattern >>= bits;
+                mask >>= bits;
+            }
+        };
+    }
+}
+
+/// Emits code loading a counter `value` into ISR.
+///
+/// Counters are kept bit-reversed so they can be built with the right
+/// in-shift direction the sampler uses: `set` only carries 5 bits, so wider
+/// values are shifted in through Y five bits at a time. Read the counter
+/// back with `mov y, ::isr`. Clobbers Y.
+///
+/// # Arguments
+///
+/// * `asm` - Assembler to emit instructions into.
+/// * `value` - Counter value, saturated to 30 bits to stay clear of autopush.
+fn load_counter<const N: usize>(asm: &mut pio::Assembler<N>, value: u32) {
+    let value = value.min((1 << 30) - 1);
+    if value < 32 {
+        asm.set(pio::SetDestination::Y, value as _);
+        asm.mov(
+            pio::MovDestination::ISR,
+            pio::MovOperation::BitReverse,
+            pio::MovSource::Y,
+        );
+        return;
+    }
+
+    asm.mov(
+        pio::MovDestination::ISR,
+        pio::MovOperation::None,
+        pio::MovSource::NULL,
+    );
+    let chunks = (32 - value.leading_zeros()).div_ceil(5);
+    for chunk in (0..chunks).rev() {
+        let bits = (value >> (chunk * 5) & 0x1f) as u8;
+        asm.set(pio::SetDestination::Y, bits.reverse_bits() >> 3);
+        asm.r#in(pio::InSource::Y, 5);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{Pio, StateMachine};
+
+    /// Returns `len` samples idling at zero with `value` applied from index `from` on.
+    fn hold(len: usize, from: usize, value: u32) -> Vec<u32> {
+        (0..len).map(|i| if i < from { 0 } else { value }).collect()
+    }
+
+    /// Returns `len` samples with `value` asserted for 8 out of every 16 samples.
+    fn pulses(len: usize, value: u32) -> Vec<u32> {
+        (0..len)
+            .map(|i| if i % 16 < 8 { 0 } else { value })
+            .collect()
+    }
+
+    #[test]
+    fn empty_trigger_fires_immediately() {
+        let trigger = Trigger::default();
+        // Loading the pre-trigger count takes the first 3 cycles.
+        assert_eq!(Pio::new(&trigger).fire(&hold(8, 0, 0)), Some(3));
+    }
+
+    #[test]
+    fn single_bit_stage() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 1 << 3);
+        trigger.set_pattern(0, 1 << 3);
+        // The stage loop samples every 3 cycles from 3, the match at 12 fires at 15.
+        assert_eq!(Pio::new(&trigger).fire(&hold(64, 10, 1 << 3)), Some(15));
+        assert_eq!(Pio::new(&trigger).fire(&hold(64, 10, 1 << 2)), None);
+    }
+
+    #[test]
+    fn single_bit_low_pattern() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 1);
+        assert_eq!(Pio::new(&trigger).fire(&hold(64, 0, 0)), Some(6));
+        assert_eq!(Pio::new(&trigger).fire(&hold(64, 0, 1)), None);
+    }
+
+    #[test]
+    fn multi_bit_pattern_follows_pin_order() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 0b0111_0000);
+        trigger.set_pattern(0, 0b0001_0000);
+        assert_eq!(Pio::new(&trigger).fire(&hold(64, 0, 0b0100_0000)), None);
+        assert_eq!(Pio::new(&trigger).fire(&hold(64, 0, 0b0001_0000)), Some(8));
+    }
+
+    #[test]
+    fn wide_pattern() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 0xffff);
+        trigger.set_pattern(0, 0xa5c3);
+        assert_eq!(Pio::new(&trigger).fire(&hold(64, 0, 0xa5c2)), None);
+        assert_eq!(Pio::new(&trigger).fire(&hold(64, 0, 0x3c5a)), None);
+        assert!(Pio::new(&trigger).fire(&hold(64, 0, 0xa5c3)).is_some());
+    }
+
+    #[test]
+    fn stages_match_in_sequence() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 1);
+        trigger.set_pattern(0, 1);
+        trigger.set_mask(1, 2);
+        trigger.set_pattern(1, 2);
+
+        // Stage 1 alone never fires.
+        assert_eq!(Pio::new(&trigger).fire(&hold(64, 0, 2)), None);
+
+        // Stage 0 matches at 12, stage 1 samples every 4 cycles from 15 and matches at 23.
+        let mut samples = hold(64, 10, 1);
+        samples[20..].fill(2);
+        assert_eq!(Pio::new(&trigger).fire(&samples), Some(27));
+    }
+
+    #[test]
+    fn count_waits_for_occurrences() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 1);
+        trigger.set_pattern(0, 1);
+        trigger.set_count(0, 3);
+        let fired = Pio::new(&trigger).fire(&pulses(128, 1)).unwrap();
+        assert!((40..48).contains(&fired), "fired at {fired}");
+    }
+
+    #[test]
+    fn wide_count_waits_for_occurrences() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 1);
+        trigger.set_pattern(0, 1);
+        trigger.set_count(0, 40);
+        let fired = Pio::new(&trigger).fire(&pulses(1024, 1)).unwrap();
+        assert!((40 * 16 - 8..40 * 16).contains(&fired), "fired at {fired}");
+    }
+
+    #[test]
+    fn window_restarts_sequence() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 1);
+        trigger.set_pattern(0, 1);
+        trigger.set_window(0, 40);
+        trigger.set_mask(1, 2);
+        trigger.set_pattern(1, 2);
+
+        // Stage 1 follows within the window.
+        let mut samples = hold(256, 10, 1);
+        samples[16..].fill(0);
+        samples[40..].fill(2);
+        assert!(Pio::new(&trigger).fire(&samples).is_some());
+
+        // Stage 1 comes too late, the sequence restarts and waits for stage 0 again.
+        samples[40..].fill(0);
+        samples[80..].fill(2);
+        assert_eq!(Pio::new(&trigger).fire(&samples), None);
+        samples[160..].fill(3);
+        assert!(Pio::new(&trigger).fire(&samples).unwrap() > 160);
+    }
+
+    #[test]
+    fn hold_requires_persistent_pattern() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 1);
+        trigger.set_pattern(0, 1);
+        trigger.set_hold(0, 30);
+
+        // Glitches shorter than the hold are ignored.
+        assert_eq!(Pio::new(&trigger).fire(&pulses(256, 1)), None);
+
+        let fired = Pio::new(&trigger).fire(&hold(256, 100, 1)).unwrap();
+        assert!((130..150).contains(&fired), "fired at {fired}");
+    }
+
+    /// Checks the range trigger against brute-force evaluation for the given bus values.
+    fn check_range(channel: u8, width: u8, min: u16, max: u16, values: &[u32]) {
+        for outside in [false, true] {
+            let mut trigger = Trigger::default();
+            trigger.set_range(0, Some((channel, width, min, max, outside)));
+            // Channels around the bus carry noise the comparison must ignore.
+            let noise = 0xa5a5 & !(((1 << width) - 1) << channel);
+            for &value in values {
+                let inside = (min as u32..=max as u32).contains(&value);
+                let samples = hold(96, 0, value << channel | noise);
+                assert_eq!(
+                    Pio::new(&trigger).fire(&samples).is_some(),
+                    inside != outside,
+                    "{min:#x}..={max:#x} outside: {outside} value: {value:#x}"
+                );
             }
         }
+    }
 
-        // Bind the wrap target and source labels.
-        asm.bind(&mut wrap_target);
-        asm.r#in(pio::InSource::PINS, PROBES as _);
-        asm.bind(&mut wrap_source);
+    #[test]
+    fn byte_range_matches_brute_force() {
+        let values: Vec<u32> = (0..256).collect();
+        for (min, max) in [
+            (0x20, 0x7e),
+            (0x00, 0x0f),
+            (0xf0, 0xff),
+            (0x41, 0x41),
+            (0x00, 0xff),
+            (0x7f, 0x80),
+            (0x03, 0xfc),
+        ] {
+            check_range(4, 8, min, max, &values);
+        }
+    }
 
-        // Assemble the program with wrap points and set the origin.
-        asm.assemble_with_wrap(wrap_source, wrap_target)
-            .set_origin(Some(0))
+    #[test]
+    fn irregular_range_is_rejected() {
+        let mut trigger = Trigger::default();
+        assert!(!trigger.set_range(0, Some((0, 8, 0x55, 0xaa, false))));
+        assert!(!trigger.set_range(0, Some((0, 8, 0x35, 0xca, true))));
+        assert_eq!(trigger.stages(), Trigger::default().stages());
+    }
+
+    #[test]
+    fn word_range_matches_brute_force() {
+        let mut values = vec![0, 0xffff];
+        let mut seed = 1u32;
+        for _ in 0..300 {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            values.push(seed >> 16);
+        }
+        for (min, max) in [(0x1000, 0x1fff), (0x0100, 0xc000), (0x8000, 0x8003)] {
+            let mut values = values.clone();
+            values.extend([min - 1, min, max, max + 1].map(|v| v as u32));
+            check_range(0, 16, min, max, &values);
+        }
+    }
+
+    #[test]
+    fn external_input_level() {
+        let mut trigger = Trigger::default();
+        trigger.set_external(0, Some((16, true, false)));
+        assert_eq!(Pio::new(&trigger).fire(&hold(64, 0, 1 << 16)), None);
+
+        let samples: Vec<_> = hold(64, 10, 1 << 16).iter().map(|s| s ^ 1 << 16).collect();
+        assert_eq!(Pio::new(&trigger).fire(&samples), Some(11));
+    }
+
+    #[test]
+    fn external_input_edge() {
+        let mut trigger = Trigger::default();
+        trigger.set_external(0, Some((16, false, true)));
+        // Already high at start, the rising edge is missing.
+        assert_eq!(Pio::new(&trigger).fire(&hold(64, 0, 1 << 16)), None);
+        assert_eq!(Pio::new(&trigger).fire(&hold(64, 10, 1 << 16)), Some(11));
+    }
+
+    #[test]
+    fn or_groups_fire_on_any_group() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 1);
+        trigger.set_pattern(0, 1);
+        trigger.set_mask(1, 2);
+        trigger.set_pattern(1, 2);
+        trigger.set_group(1, 1);
+        assert!(Pio::new(&trigger).fire(&hold(64, 10, 1)).is_some());
+        assert!(Pio::new(&trigger).fire(&hold(64, 10, 2)).is_some());
+        assert_eq!(Pio::new(&trigger).fire(&hold(64, 10, 4)), None);
+    }
+
+    #[test]
+    fn or_groups_ignore_pretrigger_matches() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 1);
+        trigger.set_pattern(0, 1);
+        trigger.set_mask(1, 2);
+        trigger.set_pattern(1, 2);
+        trigger.set_group(1, 1);
+        // Group 0 matches within the 100 pre-trigger cycles, group 1 only later.
+        let mut samples = hold(400, 200, 2);
+        samples[10..20].fill(1);
+        let fired = Pio::with_counts(&trigger, 100, 0).fire(&samples);
+        assert!(fired.is_some_and(|at| at >= 200));
+        assert_eq!(
+            Pio::with_counts(&trigger, 100, 0).fire(&samples[..200]),
+            None
+        );
+    }
+
+    /// Returns the number of instructions of the assembled trigger and helper programs.
+    fn assembled_len(trigger: &Trigger) -> usize {
+        let helpers = trigger.helpers();
+        let helpers = helpers.iter().flatten().map(|h| h.program.code.len());
+        trigger.compile().code.len() + helpers.sum::<usize>()
+    }
+
+    #[test]
+    fn len_matches_the_assembled_programs() {
+        let mut trigger = Trigger::default();
+        assert_eq!(trigger.len(), assembled_len(&trigger));
+        trigger.set_mask(0, 1);
+        trigger.set_pattern(0, 1);
+        trigger.set_window(0, 100);
+        trigger.set_mask(1, 0b0110);
+        trigger.set_output(Some((20, false, false)));
+        assert_eq!(trigger.len(), assembled_len(&trigger));
+
+        let mut held = Trigger::default();
+        held.set_mask(0, 0b0110);
+        held.set_hold(0, 40);
+        assert_eq!(held.len(), assembled_len(&held));
+
+        let mut counted = Trigger::default();
+        counted.set_mask(0, 0b0011);
+        counted.set_count(0, 40);
+        counted.set_external(1, Some((16, false, true)));
+        counted.set_hold(1, 3);
+        assert_eq!(counted.len(), assembled_len(&counted));
+
+        trigger.set_group(1, 1);
+        assert_eq!(trigger.len(), assembled_len(&trigger));
+        trigger.set_protocol(ProtocolTrigger::new(1, [3, 8, 2]));
+        assert_eq!(trigger.len(), assembled_len(&trigger));
+    }
+
+    #[test]
+    fn protocol_settings_carry_over_to_the_decoder() {
+        let mut trigger = Trigger::default();
+        trigger.set_protocol_value(0x41);
+        trigger.set_protocol_rate(9600);
+        trigger.set_protocol(ProtocolTrigger::new(1, [0, 8, 1]));
+        let mut uart = ProtocolTrigger::new(1, [0, 8, 1]).unwrap();
+        uart.set_value(0x41);
+        uart.set_rate(9600);
+        let helpers = trigger.helpers();
+        let program = helpers[1].as_ref().unwrap();
+        assert_eq!(program.preload, Some(0x41));
+        assert_eq!(program.divisor, uart.compile().divisor);
+
+        // Switching decoders keeps the settings, later changes reach the decoder.
+        trigger.set_protocol(ProtocolTrigger::new(2, [0, 1, 0]));
+        trigger.set_protocol_value(0x50);
+        assert_eq!(trigger.helpers()[1].as_ref().unwrap().preload, Some(0xa0));
+    }
+
+    #[test]
+    fn oversized_trigger_does_not_fit() {
+        // Two wide sparse groups don't fit next to each other.
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 0x5555);
+        trigger.set_mask(1, 0x5555);
+        trigger.set_group(1, 1);
+        assert!(trigger.len() > PIO_LEN);
+        assert!(!trigger.fits());
+
+        // A sparse 32-bit mask with count, window and hold is sized without panicking.
+        let mut trigger = Trigger::default();
+        for stage in 0..4 {
+            trigger.set_mask(stage, 0x5555_5555);
+            trigger.set_count(stage, 1 << 29);
+            trigger.set_window(stage, 1 << 29);
+            trigger.set_hold(stage, 1 << 29);
+        }
+        assert!(!trigger.fits());
+    }
+
+    #[test]
+    fn settings_that_dont_fit_are_rejected() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 0x155);
+        assert!(trigger.fits());
+        assert!(!trigger.set_count(0, 1000));
+        assert!(!trigger.set_hold(0, 1000));
+        assert_eq!(trigger.stages()[0].count, 0);
+        assert_eq!(trigger.stages()[0].hold, 0);
+        trigger.set_mask(0, 1);
+        assert!(trigger.set_count(0, 1000));
+        assert!(trigger.fits());
+
+        // A sparse SPI word costs three instructions per compared bit.
+        let mut trigger = Trigger::default();
+        assert!(trigger.set_protocol(ProtocolTrigger::new(3, [0x10, 0x02, 16])));
+        assert!(!trigger.set_protocol_mask(0x5555));
+        assert!(trigger.set_protocol_mask(0xff00));
+        assert!(trigger.fits());
+
+        // Settings kept without a decoder are checked once one is selected.
+        let mut trigger = Trigger::default();
+        assert!(trigger.set_protocol_mask(0x5555));
+        assert!(!trigger.set_protocol(ProtocolTrigger::new(3, [0x10, 0x02, 16])));
+        assert!(!trigger.has_helpers());
+    }
+
+    #[test]
+    fn ranges_past_the_probes_are_rejected() {
+        let mut trigger = Trigger::default();
+        assert!(trigger.set_range(0, Some((8, 8, 1, 2, false))));
+        let range = trigger.stages()[0].range;
+        assert!(!trigger.set_range(0, Some((8, 0, 1, 2, false))));
+        assert!(!trigger.set_range(0, Some((12, 8, 1, 2, false))));
+        assert!(!trigger.set_range(0, Some((255, 2, 1, 2, false))));
+        assert_eq!(trigger.stages()[0].range, range);
+        assert!(trigger.set_range(0, None));
+        assert_eq!(trigger.stages()[0].range, None);
+    }
+
+    #[test]
+    fn output_follows_trigger() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 1);
+        trigger.set_pattern(0, 1);
+        trigger.set_output(Some((20, true, false)));
+        let mut pio = Pio::new(&trigger);
+        for pins in hold(8, 0, 0) {
+            pio.step(pins);
+            assert_eq!(pio.sms[0].outputs, 1 << 20);
+        }
+        pio.fire(&hold(16, 0, 1)).unwrap();
+        assert_eq!(pio.sms[0].outputs, 0);
+    }
+
+    #[test]
+    fn pretrigger_samples_pass_before_arming() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 1);
+        trigger.set_pattern(0, 1);
+        // A match while the ring fills is ignored.
+        let mut samples = pulses(256, 1);
+        samples[..40].fill(1);
+        samples[40..64].fill(0);
+        let fired = Pio::with_counts(&trigger, 100, 0).fire(&samples).unwrap();
+        assert!((104..120).contains(&fired), "fired at {fired}");
+    }
+
+    #[test]
+    fn posttrigger_samples_pass_before_stop() {
+        let trigger = Trigger::default();
+        let samples = hold(128, 0, 0);
+        // Fires at 3, loading the count and pushing the stop word take 3 more cycles.
+        assert_eq!(
+            Pio::with_counts(&trigger, 0, 50).stop(&samples),
+            Some(3 + 50 + 3)
+        );
+    }
+
+    #[test]
+    fn second_arm_starts_a_fresh_capture() {
+        let trigger = Trigger::default();
+        let mut pio = Pio::with_counts(&trigger, 0, 50);
+        let samples = hold(128, 0, 0);
+        let stop = pio.stop(&samples).unwrap();
+        // The stop word of the last capture must not stop the next one at once.
+        pio.arm(0, 50);
+        assert_eq!(pio.stop(&samples), Some(stop));
+    }
+
+    #[test]
+    fn rearm_waits_for_next_counts() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 1);
+        trigger.set_pattern(0, 1);
+        trigger.set_rearm(true);
+        let mut pio = Pio::with_counts(&trigger, 0, 10);
+        let samples = pulses(64, 1);
+        // The first pulse fires, the stop word follows the post-trigger count.
+        let stop = pio.stop(&samples).unwrap();
+        assert!((8 + 10..16 + 10).contains(&stop), "stopped at {stop}");
+
+        // Nothing happens until the next counts arrive, then the next pulse fires.
+        // `Sampler::rearm` reads the stop word, the next segment waits for its own.
+        assert_eq!(pio.sms[0].rx.pop_front(), Some(0));
+        assert_eq!(pio.stop(&samples), None);
+        pio.sms[0].write(0);
+        pio.sms[0].write(10);
+        assert_eq!(pio.stop(&samples), Some(stop));
+    }
+
+    #[test]
+    fn unread_stop_words_stall_the_segments() {
+        let mut trigger = Trigger::default();
+        trigger.set_rearm(true);
+        let mut pio = Pio::with_counts(&trigger, 0, 0);
+        let samples = hold(64, 0, 0);
+        // Without reading the stop words the FIFO fills up after 4 segments.
+        for _ in 0..5 {
+            pio.fire(&samples);
+            pio.sms[0].write(0);
+            pio.sms[0].write(0);
+        }
+        assert_eq!(pio.sms[0].rx.len(), 4);
+        assert_eq!(pio.fire(&samples), None);
+    }
+
+    #[test]
+    fn force_trigger_restarts_into_capture() {
+        let mut trigger = Trigger::default();
+        trigger.set_mask(0, 1);
+        trigger.set_pattern(0, 1);
+        let mut pio = Pio::new(&trigger);
+        assert_eq!(pio.fire(&hold(16, 0, 0)), None);
+        pio.sms[0].restart();
+        assert_eq!(pio.fire(&hold(16, 0, 0)), Some(0));
+    }
+
+    /// Runs the transition detector over `samples` and decodes its records.
+    ///
+    /// Returns the clock of each recorded value, counted from the first record.
+    fn transitions(samples: &[u32]) -> Vec<(usize, u32)> {
+        let mut sm = StateMachine::transitions();
+        let mut irq = 0;
+        // The ring DMA keeps the FIFO empty.
+        let mut records = Vec::new();
+        for pins in samples {
+            sm.step(*pins, &mut irq);
+            records.extend(sm.rx.drain(..));
+        }
+        let mut clock = 0;
+        records
+            .iter()
+            .enumerate()
+            .map(|(idx, record)| {
+                if idx > 0 {
+                    clock += transition_ticks(*record) as usize * TRANSITION_TICK as usize;
+                }
+                (clock, record >> 16)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn transitions_record_changes_only() {
+        let mut samples = hold(1200, 100, 0x5a);
+        samples[1000..].fill(0x1234);
+        // Auxiliary pins are not recorded.
+        for (idx, pins) in samples.iter_mut().enumerate() {
+            *pins |= (idx as u32 & 1) << 20;
+        }
+        // The first sample is taken at 3, the changes are seen at 101 and 1004.
+        assert_eq!(transitions(&samples), [(0, 0), (98, 0x5a), (1001, 0x1234)]);
+    }
+
+    #[test]
+    fn capture_packs_narrow_samples() {
+        // Channels 8-11 of the second group, one nibble per sample.
+        let mut sm = StateMachine::capture(4, 8);
+        let mut irq = 0;
+        for sample in 0..16 {
+            sm.step((sample << 8) | 0xff, &mut irq);
+        }
+        assert_eq!(sm.rx, [0x7654_3210, 0xfedc_ba98]);
+    }
+
+    #[test]
+    fn transitions_keep_alive_when_idle() {
+        let idle = 0x10001 * TRANSITION_TICK as usize;
+        let samples = hold(idle + 16, 0, 0xff);
+        assert_eq!(transitions(&samples), [(0, 0xff), (idle, 0xff)]);
+    }
+
+    /// Runs a helper program on its own state machine and returns the sample it raises `TRIGGER_IRQ` at.
+    fn decode(helper: &HelperProgram, samples: &[u32]) -> Option<usize> {
+        let mut sm = StateMachine::helper(helper, 1);
+        let mut irq = 0;
+        samples.iter().position(|pins| {
+            sm.step(*pins, &mut irq);
+            irq & 1 << TRIGGER_IRQ != 0
+        })
+    }
+
+    /// Returns `bit` samples per bit of UART frames with `data_bits` data bits on `channel`,
+    /// each followed by a stop and an idle bit.
+    fn uart(channel: u8, data_bits: u8, bytes: &[u8], bit: usize) -> Vec<u32> {
+        let mut levels = vec![1, 1];
+        for byte in bytes {
+            levels.push(0);
+            levels.extend((0..data_bits).map(|i| (*byte as u32) >> i & 1));
+            levels.extend([1, 1]);
+        }
+        levels
+            .iter()
+            .flat_map(|level| core::iter::repeat_n(level << (PIN_BASE + channel as usize), bit))
+            .collect()
+    }
+
+    #[test]
+    fn uart_decoder_matches_byte_sequence() {
+        let mut protocol = ProtocolTrigger::new(1, [2, 8, 2]).unwrap();
+        protocol.set_rate(sample_rate() / 64);
+        protocol.set_value(u32::from_le_bytes(*b"AB\0\0"));
+        let helper = protocol.compile();
+        // Eight PIO cycles per bit.
+        let bit = 8 * helper.divisor.unwrap().0 as usize;
+        assert_eq!(bit, 64);
+
+        // Fires after the last data bit of the second frame, in its stop bit.
+        let fired = decode(&helper, &uart(2, 8, b"AB", bit));
+        assert!(fired.is_some_and(|at| (22 * bit..23 * bit).contains(&at)));
+        assert_eq!(decode(&helper, &uart(2, 8, b"AC", bit)), None);
+        assert_eq!(decode(&helper, &uart(2, 8, b"BA", bit)), None);
+        // The frames on another channel leave the decoded line low.
+        assert_eq!(decode(&helper, &uart(3, 8, b"AB", bit)), None);
+    }
+
+    #[test]
+    fn uart_decoder_matches_short_frames() {
+        for data_bits in 5..=7 {
+            let mut protocol = ProtocolTrigger::new(1, [2, data_bits, 3]).unwrap();
+            protocol.set_rate(sample_rate() / 64);
+            protocol.set_value(u32::from_le_bytes([0x15, 0x0a, 0x13, 0]));
+            let helper = protocol.compile();
+            let bit = 8 * helper.divisor.unwrap().0 as usize;
+
+            // Fires after the last data bit of the third frame, in its stop bit.
+            let last = 3 * data_bits as usize + 9;
+            let fired = decode(&helper, &uart(2, data_bits, &[0x15, 0x0a, 0x13], bit));
+            assert!(fired.is_some_and(|at| (last * bit..(last + 1) * bit).contains(&at)));
+            // The top data bit of a frame is compared too.
+            let flipped = 0x13 ^ 1 << (data_bits - 1);
+            let frames = [0x15, 0x0a, flipped];
+            assert_eq!(decode(&helper, &uart(2, data_bits, &frames, bit)), None);
+        }
+    }
+
+    /// Returns the samples of an I2C transfer: START, `bytes` MSB first with an ACK each, STOP.
+    fn i2c(sda: u8, scl: u8, bytes: &[u8]) -> Vec<u32> {
+        // Levels of SDA and SCL, SDA only changes while SCL is low.
+        let mut levels = vec![(1, 1), (0, 1)];
+        for byte in bytes {
+            for bit in (0..8).rev().map(|i| (*byte as u32) >> i & 1).chain([0]) {
+                levels.extend([(bit, 0), (bit, 1), (bit, 0)]);
+            }
+        }
+        levels.extend([(0, 0), (0, 1), (1, 1)]);
+        let pins = |(sda_level, scl_level): (u32, u32)| {
+            sda_level << (PIN_BASE + sda as usize) | scl_level << (PIN_BASE + scl as usize)
+        };
+        levels
+            .into_iter()
+            .flat_map(|level| core::iter::repeat_n(pins(level), 4))
+            .collect()
+    }
+
+    #[test]
+    fn i2c_decoder_matches_address_and_data() {
+        let mut protocol = ProtocolTrigger::new(2, [4, 5, 0]).unwrap();
+        protocol.set_value(0x50);
+        let helper = protocol.compile();
+        // The ACK clock rises at 108, the comparison takes 5 cycles.
+        assert_eq!(decode(&helper, &i2c(4, 5, &[0xa0])), Some(113));
+        // A read from the same address, or a write to another one.
+        assert_eq!(decode(&helper, &i2c(4, 5, &[0xa1])), None);
+        assert_eq!(decode(&helper, &i2c(4, 5, &[0xa2])), None);
+
+        // The data byte following the address is compared too.
+        let mut protocol = ProtocolTrigger::new(2, [4, 5, 2]).unwrap();
+        protocol.set_value(0x33 << 16 | 0x50);
+        let helper = protocol.compile();
+        assert_eq!(decode(&helper, &i2c(4, 5, &[0xa0, 0x33])), Some(221));
+        assert_eq!(decode(&helper, &i2c(4, 5, &[0xa0, 0x34])), None);
+
+        // A 10-bit read addresses the device with the write header and the low byte first.
+        let mut protocol = ProtocolTrigger::new(2, [4, 5, 1]).unwrap();
+        protocol.set_value(1 << 10 | 0x2a5);
+        let helper = protocol.compile();
+        let read = [i2c(4, 5, &[0xf4, 0xa5]), i2c(4, 5, &[0xf5, 0x5a])].concat();
+        assert!(decode(&helper, &read).is_some());
+        let read = [i2c(4, 5, &[0xf4, 0xa6]), i2c(4, 5, &[0xf5, 0x5a])].concat();
+        assert_eq!(decode(&helper, &read), None);
+    }
+
+    /// Returns the samples of an SPI transfer of `words` MSB first while chip select is low.
+    ///
+    /// Every level lasts 16 cycles, leaving time to match a word before the next one.
+    fn spi(mode: u8, cs: u8, sck: u8, data: u8, words: &[u8]) -> Vec<u32> {
+        // Levels of chip select, the clock and the data line.
+        let idle = (mode >> 1) as u32;
+        let mut levels = vec![(1, idle, 0), (0, idle, 0)];
+        let mut last = 0;
+        for word in words {
+            for bit in (0..8).rev().map(|i| (*word as u32) >> i & 1) {
+                // Data changes on the edge before the sampling one, lagging the
+                // leading edge when the trailing one samples.
+                match mode & 1 {
+                    0 => levels.extend([(0, idle, bit), (0, idle ^ 1, bit)]),
+                    _ => levels.extend([(0, idle ^ 1, last), (0, idle ^ 1, bit), (0, idle, bit)]),
+                }
+                last = bit;
+            }
+        }
+        levels.extend([(0, idle, 0), (1, idle, 0)]);
+        let pins = |(cs_level, sck_level, data_level): (u32, u32, u32)| {
+            cs_level << (PIN_BASE + cs as usize)
+                | sck_level << (PIN_BASE + sck as usize)
+                | data_level << (PIN_BASE + data as usize)
+        };
+        levels
+            .into_iter()
+            .flat_map(|level| core::iter::repeat_n(pins(level), 16))
+            .collect()
+    }
+
+    #[test]
+    fn spi_decoder_matches_masked_word() {
+        let mut protocol = ProtocolTrigger::new(3, [0x10, 0x02, 8]).unwrap();
+        protocol.set_mask(0xf0);
+        protocol.set_value(0xa0);
+        let helper = protocol.compile();
+        // The last clock of the word rises at 272, the match takes 8 cycles.
+        assert_eq!(decode(&helper, &spi(0, 0, 1, 2, &[0xa5])), Some(280));
+        assert_eq!(decode(&helper, &spi(0, 0, 1, 2, &[0x5a, 0xa5])), Some(536));
+        assert_eq!(decode(&helper, &spi(0, 0, 1, 2, &[0x5a])), None);
+        assert_eq!(decode(&helper, &spi(0, 0, 1, 2, &[0x0a, 0x50])), None);
+
+        // Sparse masks compare single bits in word order.
+        protocol.set_mask(0x81);
+        protocol.set_value(0x01);
+        let helper = protocol.compile();
+        assert!(decode(&helper, &spi(0, 0, 1, 2, &[0x7f])).is_some());
+        assert_eq!(decode(&helper, &spi(0, 0, 1, 2, &[0xfe])), None);
+    }
+
+    #[test]
+    fn spi_decoder_follows_clock_mode() {
+        for mode in 1..=3 {
+            let mut protocol = ProtocolTrigger::new(3, [0x10, 0x02 | mode << 4, 8]).unwrap();
+            protocol.set_mask(0xff);
+            protocol.set_value(0xa5);
+            let helper = protocol.compile();
+            // The last sampling edge of the word comes 17 levels in, 25 with lagging data.
+            let edge = match mode & 1 {
+                0 => 272,
+                _ => 400,
+            };
+            let fired = decode(&helper, &spi(mode, 0, 1, 2, &[0xa5]));
+            assert!(fired.is_some_and(|at| (edge..edge + 16).contains(&at)));
+            assert!(decode(&helper, &spi(mode, 0, 1, 2, &[0x5a, 0xa5])).is_some());
+            assert_eq!(decode(&helper, &spi(mode, 0, 1, 2, &[0xa4])), None);
+            assert_eq!(decode(&helper, &spi(mode, 0, 1, 2, &[0x4a, 0x5a])), None);
+        }
     }
 }